@@ -0,0 +1,136 @@
+use crate::token::Span;
+
+// The syntax tree of a program, as the parser produces it and the compiler
+// lowers it to bytecode. Nodes keep the spans of the tokens they were
+// parsed from, so tools can map them back to the source. Names and
+// string literals borrow from the source they were parsed from
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program<'a> {
+    pub statements: Vec<Stmt<'a>>,
+    pub end: Span, // The end of the source
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Identifier<'a> {
+    pub name: &'a str,
+    pub span: Span,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnaryOp {
+    Negate, // -
+    Not,    // !
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+    Add, Sub, Mul, Div,
+    Equal, NotEqual,
+    Greater, GreaterEqual, Less, LessEqual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr<'a> {
+    Number { value: f64, span: Span },
+    String { value: &'a str, span: Span }, // The value is without its quotes
+    Bool { value: bool, span: Span },
+    Nil { span: Span },
+    Variable(Identifier<'a>),
+    Assign { name: Identifier<'a>, value: Box<Expr<'a>> },
+    This { span: Span },
+    // A method looked up on the superclass, as in `super.method`
+    Super { keyword: Span, method: Identifier<'a> },
+    Unary { op: UnaryOp, op_span: Span, operand: Box<Expr<'a>> },
+    Binary { op: BinaryOp, op_span: Span, left: Box<Expr<'a>>, right: Box<Expr<'a>> },
+    // An operator that only evaluates its right operand if it has to
+    Logical { op: LogicalOp, op_span: Span, left: Box<Expr<'a>>, right: Box<Expr<'a>> },
+    Grouping { left_paren: Span, expr: Box<Expr<'a>>, right_paren: Span },
+    Call { callee: Box<Expr<'a>>, arguments: Vec<Expr<'a>>, right_paren: Span },
+    Get { object: Box<Expr<'a>>, name: Identifier<'a> },
+    Set { object: Box<Expr<'a>>, name: Identifier<'a>, value: Box<Expr<'a>> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stmt<'a> {
+    Expression { expr: Expr<'a>, semicolon: Span },
+    Print { keyword: Span, expr: Expr<'a>, semicolon: Span },
+    Var { name: Identifier<'a>, initializer: Option<Expr<'a>>, semicolon: Span },
+    Function(Function<'a>),
+    Class(Class<'a>),
+    Block { left_brace: Span, statements: Vec<Stmt<'a>>, right_brace: Span },
+    If {
+        keyword: Span,
+        condition: Expr<'a>,
+        right_paren: Span, // After the condition
+        then_branch: Box<Stmt<'a>>,
+        else_branch: Option<Box<Stmt<'a>>>,
+    },
+    While { keyword: Span, condition: Expr<'a>, right_paren: Span, body: Box<Stmt<'a>> },
+    For {
+        keyword: Span,
+        initializer: Option<Box<Stmt<'a>>>, // A `var` or expression statement
+        condition: Option<Expr<'a>>,
+        semicolon: Span, // After the condition, even if there isn't one
+        increment: Option<Expr<'a>>,
+        right_paren: Span,
+        body: Box<Stmt<'a>>,
+    },
+    Return { keyword: Span, value: Option<Expr<'a>>, semicolon: Span },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function<'a> {
+    pub name: Identifier<'a>,
+    pub params: Vec<Identifier<'a>>,
+    pub body: Vec<Stmt<'a>>,
+    pub right_brace: Span, // The end of the body
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Class<'a> {
+    pub name: Identifier<'a>,
+    pub superclass: Option<Identifier<'a>>,
+    pub methods: Vec<Function<'a>>,
+    pub right_brace: Span,
+}
+
+impl Expr<'_> {
+    // The span of the expression's last token
+    pub fn last_span(&self) -> Span {
+        match self {
+            Expr::Number { span, .. } | Expr::String { span, .. } | Expr::Bool { span, .. }
+            | Expr::Nil { span } | Expr::This { span } => *span,
+            Expr::Variable(name) => name.span,
+            Expr::Super { method, .. } => method.span,
+            Expr::Get { name, .. } => name.span,
+            Expr::Grouping { right_paren, .. } | Expr::Call { right_paren, .. } => *right_paren,
+            Expr::Assign { value, .. } | Expr::Set { value, .. } => value.last_span(),
+            Expr::Unary { operand, .. } => operand.last_span(),
+            Expr::Binary { right, .. } | Expr::Logical { right, .. } => right.last_span(),
+        }
+    }
+}
+
+impl Stmt<'_> {
+    // The span of the statement's last token
+    pub fn last_span(&self) -> Span {
+        match self {
+            Stmt::Expression { semicolon, .. } | Stmt::Print { semicolon, .. }
+            | Stmt::Var { semicolon, .. } | Stmt::Return { semicolon, .. } => *semicolon,
+            Stmt::Function(function) => function.right_brace,
+            Stmt::Class(class) => class.right_brace,
+            Stmt::Block { right_brace, .. } => *right_brace,
+            Stmt::If { then_branch, else_branch, .. } => {
+                else_branch.as_ref().unwrap_or(then_branch).last_span()
+            },
+            Stmt::While { body, .. } | Stmt::For { body, .. } => body.last_span(),
+        }
+    }
+}
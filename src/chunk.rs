@@ -1,126 +1,356 @@
-use crate::value::Value;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
 
+use crate::heap::{Heap, ObjRef};
+use crate::token::Span;
+use crate::value::{Obj, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
 pub enum Opcode {
-    Return,
-    Constant,
-    Nil,
-    True,
-    False,
-    Neg,
-    Not,
-    Add,
-    Sub,
-    Mul,
-    Div,
-    Equal,
-    Greater,
-    Less,
-    Error,
+    Return = 0,
+    Constant = 1,
+    Nil = 2,
+    True = 3,
+    False = 4,
+    Neg = 5,
+    Not = 6,
+    Add = 7,
+    Sub = 8,
+    Mul = 9,
+    Div = 10,
+    Equal = 11,
+    Greater = 12,
+    Less = 13,
+    Print = 14,
+    Pop = 15,
+    DefineGlobal = 16,
+    GetGlobal = 17,
+    SetGlobal = 18,
+    GetLocal = 19,
+    SetLocal = 20,
+    Jump = 21,
+    JumpIfFalse = 22,
+    Loop = 23,
+    Call = 24,
+    Closure = 25,
+    GetUpvalue = 26,
+    SetUpvalue = 27,
+    CloseUpvalue = 28,
+    Class = 29,
+    GetProperty = 30,
+    SetProperty = 31,
+    Method = 32,
+    Invoke = 33,
+    Inherit = 34,
+    GetSuper = 35,
+    SuperInvoke = 36,
+    ConstantLong = 37, // Like Constant, with a 24-bit operand for big constant pools
+    // Like the instructions they're named after, with a 24-bit constant operand
+    DefineGlobalLong = 38,
+    GetGlobalLong = 39,
+    SetGlobalLong = 40,
+    ClosureLong = 41,
+    ClassLong = 42,
+    GetPropertyLong = 43,
+    SetPropertyLong = 44,
+    MethodLong = 45,
+    InvokeLong = 46,
+    GetSuperLong = 47,
+    SuperInvokeLong = 48,
+    GreaterEqual = 49,
+    LessEqual = 50,
+    NotEqual = 51,
+    Error = 255, // Any byte that isn't an opcode
+}
+
+impl Opcode {
+    // The form of an instruction with a constant operand that takes
+    // a 24-bit operand, for constants past the first 256 in the pool
+    pub fn long(self) -> Opcode {
+        match self {
+            Opcode::Constant => Opcode::ConstantLong,
+            Opcode::DefineGlobal => Opcode::DefineGlobalLong,
+            Opcode::GetGlobal => Opcode::GetGlobalLong,
+            Opcode::SetGlobal => Opcode::SetGlobalLong,
+            Opcode::Closure => Opcode::ClosureLong,
+            Opcode::Class => Opcode::ClassLong,
+            Opcode::GetProperty => Opcode::GetPropertyLong,
+            Opcode::SetProperty => Opcode::SetPropertyLong,
+            Opcode::Method => Opcode::MethodLong,
+            Opcode::Invoke => Opcode::InvokeLong,
+            Opcode::GetSuper => Opcode::GetSuperLong,
+            Opcode::SuperInvoke => Opcode::SuperInvokeLong,
+            _ => self,
+        }
+    }
+
+    pub fn is_long(self) -> bool {
+        matches!(self, Opcode::ConstantLong | Opcode::DefineGlobalLong | Opcode::GetGlobalLong
+            | Opcode::SetGlobalLong | Opcode::ClosureLong | Opcode::ClassLong | Opcode::GetPropertyLong
+            | Opcode::SetPropertyLong | Opcode::MethodLong | Opcode::InvokeLong | Opcode::GetSuperLong
+            | Opcode::SuperInvokeLong)
+    }
 }
 
 impl From<Opcode> for u8 {
     fn from(opcode: Opcode) -> u8 {
-        match opcode {
-            Opcode::Return   => 0,
-            Opcode::Constant => 1,
-            Opcode::Nil      => 2,
-            Opcode::True     => 3,
-            Opcode::False    => 4,
-            Opcode::Neg      => 5,
-            Opcode::Not      => 6,
-            Opcode::Add      => 7,
-            Opcode::Sub      => 8,
-            Opcode::Mul      => 9,
-            Opcode::Div      => 10,
-            Opcode::Equal    => 11,
-            Opcode::Greater  => 12,
-            Opcode::Less     => 13,
-            // This should never be used
-            Opcode::Error    => std::u8::MAX,
-        }
+        opcode as u8
     }
 }
 
+// Every opcode, in encoding order
+const OPCODES: [Opcode; 52] = [
+    Opcode::Return, Opcode::Constant, Opcode::Nil, Opcode::True, Opcode::False, Opcode::Neg,
+    Opcode::Not, Opcode::Add, Opcode::Sub, Opcode::Mul, Opcode::Div, Opcode::Equal, Opcode::Greater,
+    Opcode::Less, Opcode::Print, Opcode::Pop, Opcode::DefineGlobal, Opcode::GetGlobal,
+    Opcode::SetGlobal, Opcode::GetLocal, Opcode::SetLocal, Opcode::Jump, Opcode::JumpIfFalse,
+    Opcode::Loop, Opcode::Call, Opcode::Closure, Opcode::GetUpvalue, Opcode::SetUpvalue,
+    Opcode::CloseUpvalue, Opcode::Class, Opcode::GetProperty, Opcode::SetProperty, Opcode::Method,
+    Opcode::Invoke, Opcode::Inherit, Opcode::GetSuper, Opcode::SuperInvoke, Opcode::ConstantLong,
+    Opcode::DefineGlobalLong, Opcode::GetGlobalLong, Opcode::SetGlobalLong, Opcode::ClosureLong,
+    Opcode::ClassLong, Opcode::GetPropertyLong, Opcode::SetPropertyLong, Opcode::MethodLong,
+    Opcode::InvokeLong, Opcode::GetSuperLong, Opcode::SuperInvokeLong, Opcode::GreaterEqual,
+    Opcode::LessEqual, Opcode::NotEqual,
+];
+
+// What each byte decodes to, so decoding is a single load
+static DECODE: [Opcode; 256] = {
+    let mut table = [Opcode::Error; 256];
+    let mut i = 0;
+    while i < OPCODES.len() {
+        table[OPCODES[i] as usize] = OPCODES[i];
+        i += 1;
+    }
+    table
+};
+
 impl From<u8> for Opcode {
     fn from(n: u8) -> Opcode {
-        match n {
-            0  => Opcode::Return,
-            1  => Opcode::Constant,
-            2  => Opcode::Nil,
-            3  => Opcode::True,
-            4  => Opcode::False,
-            5  => Opcode::Neg,
-            6  => Opcode::Not,
-            7  => Opcode::Add,
-            8  => Opcode::Sub,
-            9  => Opcode::Mul,
-            10 => Opcode::Div,
-            11 => Opcode::Equal,
-            12 => Opcode::Greater,
-            13 => Opcode::Less,
-            _  => Opcode::Error,
+        DECODE[n as usize]
+    }
+}
+
+// The code a chunk was compiled from, kept so errors can quote it
+#[derive(Debug, Default, PartialEq)]
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+// What makes two constants identical. Numbers are keyed by their bits:
+// -0.0 and 0.0 print differently, and NaN is identical to itself but not
+// equal. Strings are interned, so equal strings have the same handle
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    Bool(bool),
+    Nil,
+    Obj(ObjRef),
+}
+
+impl From<Value> for ConstantKey {
+    fn from(value: Value) -> ConstantKey {
+        if let Some(n) = value.as_number() {
+            ConstantKey::Number(n.to_bits())
+        } else if let Some(b) = value.as_bool() {
+            ConstantKey::Bool(b)
+        } else if let Some(obj) = value.as_obj() {
+            ConstantKey::Obj(obj)
+        } else {
+            ConstantKey::Nil
         }
     }
 }
 
+#[derive(Default)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    // [run length] [line no] ...
-    lines: Vec<usize>,
+    // Runs of bytes compiled from the same span: [(run length, span)] ...
+    spans: Vec<(usize, Span)>,
     pub constants: Vec<Value>,
+    // Where each constant is in the pool, so adding one needn't search it
+    constant_indices: HashMap<ConstantKey, usize>,
+    pub source: Rc<Source>,
 }
 
 impl Chunk {
     pub fn new() -> Chunk {
+        Chunk::with_source(Rc::default())
+    }
+
+    pub fn with_source(source: Rc<Source>) -> Chunk {
         Chunk {
             code: vec![],
-            lines: vec![],
+            spans: vec![],
             constants: vec![],
+            constant_indices: HashMap::new(),
+            source,
         }
     }
 
-    pub fn line_at(&self, offset: usize) -> usize {
-        let mut current_line = 0;
+    // The span of the code that compiled to the byte at `offset`
+    pub fn span_at(&self, offset: usize) -> Span {
         let mut bytes = 0;
-        for line_info in self.lines.chunks(2) {
-            let (run_length, line_number) = (line_info[0], line_info[1]);
+        for &(run_length, span) in &self.spans {
             bytes += run_length;
-            if offset > bytes {
-                break;
+            if offset < bytes {
+                return span;
             }
-            current_line = line_number;
         }
-        current_line
+        Span::default()
     }
 
+    pub fn line_at(&self, offset: usize) -> usize {
+        self.span_at(offset).line
+    }
+
+    // Add `value` to the pool, reusing the entry of an identical constant if there is one
     pub fn add_constant(&mut self, value: Value) -> usize {
-        self.constants.push(value);
-        self.constants.len() - 1
+        let constants = &mut self.constants;
+        *self.constant_indices.entry(ConstantKey::from(value)).or_insert_with(|| {
+            constants.push(value);
+            constants.len() - 1
+        })
+    }
+
+    // Remove the constants from `len` onwards, which no code refers to any more
+    pub fn truncate_constants(&mut self, len: usize) {
+        for value in self.constants.drain(len..) {
+            self.constant_indices.remove(&ConstantKey::from(value));
+        }
+    }
+
+    // Move the constant pool of `other` into this chunk
+    pub fn take_constants(&mut self, other: &mut Chunk) {
+        self.constants = mem::take(&mut other.constants);
+        self.constant_indices = mem::take(&mut other.constant_indices);
+    }
+
+    // Remove the code from `len` onwards, along with its spans
+    pub fn truncate(&mut self, len: usize) {
+        let mut extra = self.code.len().saturating_sub(len);
+        self.code.truncate(len);
+        while extra > 0 {
+            let (run_length, _) = self.spans.last_mut().unwrap();
+            if *run_length > extra {
+                *run_length -= extra;
+                break;
+            }
+            extra -= *run_length;
+            self.spans.pop();
+        }
     }
 
-    pub fn write(&mut self, byte: u8, line_number: usize) {
+    pub fn write(&mut self, byte: u8, span: Span) {
         self.code.push(byte);
-        if !self.lines.is_empty() && self.lines.last().unwrap() == &line_number {
-            // We are still on the last line. Increment run length
-            let i = self.lines.len() - 2;
-            self.lines[i] += 1;
-            return;
+        match self.spans.last_mut() {
+            // We are still at the last span. Increment run length
+            Some((run_length, last)) if *last == span => *run_length += 1,
+            // Add an entry for a new span with run length 1
+            _ => self.spans.push((1, span)),
         }
-        // Add an entry for a new line with run length 1
-        self.lines.push(1);
-        self.lines.push(line_number);
     }
 
-    pub fn disassemble(&self, name: &str) {
+    pub fn disassemble(&self, name: &str, heap: &Heap) {
         println!("== {} ==", name);
 
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction(offset, heap);
         }
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
+    fn constant_instruction(&self, name: &str, offset: usize, heap: &Heap) -> usize {
+        let (addr, next) = self.read_constant(offset + 1, Opcode::from(self.code[offset]).is_long());
+        println!("{:16} {:4} '{}'", name, addr, self.constants[addr].display(heap));
+        next
+    }
+
+    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
+        let slot = self.code[offset + 1];
+        println!("{:16} {:4}", name, slot);
+        offset + 2
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        (self.code[offset] as u16) << 8 | self.code[offset + 1] as u16
+    }
+
+    pub fn read_u24(&self, offset: usize) -> usize {
+        (self.code[offset] as usize) << 16 | (self.code[offset + 1] as usize) << 8 | self.code[offset + 2] as usize
+    }
+
+    // Read the constant operand at `offset`, which is three bytes wide in
+    // long instructions, along with the offset just past it
+    pub fn read_constant(&self, offset: usize, long: bool) -> (usize, usize) {
+        if long {
+            (self.read_u24(offset), offset + 3)
+        } else {
+            (self.code[offset] as usize, offset + 1)
+        }
+    }
+
+    // Print a jump along with the offset it lands on
+    fn jump_instruction(&self, name: &str, forward: bool, offset: usize) -> usize {
+        let jump = self.read_u16(offset + 1) as usize;
+        let target = if forward { offset + 3 + jump } else { offset + 3 - jump };
+        println!("{:16} {:4} -> {}", name, offset, target);
+        offset + 3
+    }
+
+    fn invoke_instruction(&self, name: &str, offset: usize, heap: &Heap) -> usize {
+        let (addr, next) = self.read_constant(offset + 1, Opcode::from(self.code[offset]).is_long());
+        let arg_count = self.code[next];
+        println!("{:16} ({} args) {:4} '{}'", name, arg_count, addr, self.constants[addr].display(heap));
+        next + 1
+    }
+
+    // How many upvalues a Closure instruction for the function in constant `addr` captures
+    fn closure_upvalue_count(&self, addr: usize, heap: &Heap) -> usize {
+        match self.constants[addr].as_obj().map(|obj| heap.get(obj)) {
+            Some(Obj::Function(function)) => function.upvalue_count,
+            _ => 0,
+        }
+    }
+
+    // The length of the instruction at `offset`, including its operands
+    pub fn instruction_len(&self, offset: usize, heap: &Heap) -> usize {
+        let opcode = Opcode::from(self.code[offset]);
+        let constant_len = if opcode.is_long() { 3 } else { 1 };
+        match opcode {
+            Opcode::GetLocal | Opcode::SetLocal | Opcode::GetUpvalue | Opcode::SetUpvalue | Opcode::Call => 2,
+            Opcode::Jump | Opcode::JumpIfFalse | Opcode::Loop => 3,
+            Opcode::Constant | Opcode::ConstantLong | Opcode::DefineGlobal | Opcode::DefineGlobalLong
+            | Opcode::GetGlobal | Opcode::GetGlobalLong | Opcode::SetGlobal | Opcode::SetGlobalLong
+            | Opcode::Class | Opcode::ClassLong | Opcode::GetProperty | Opcode::GetPropertyLong
+            | Opcode::SetProperty | Opcode::SetPropertyLong | Opcode::Method | Opcode::MethodLong
+            | Opcode::GetSuper | Opcode::GetSuperLong => 1 + constant_len,
+            Opcode::Invoke | Opcode::InvokeLong | Opcode::SuperInvoke | Opcode::SuperInvokeLong => 2 + constant_len,
+            Opcode::Closure | Opcode::ClosureLong => {
+                let (addr, _) = self.read_constant(offset + 1, opcode.is_long());
+                1 + constant_len + 2 * self.closure_upvalue_count(addr, heap)
+            },
+            _ => 1,
+        }
+    }
+
+    // Print a closure along with where each of its upvalues is captured from
+    fn closure_instruction(&self, name: &str, offset: usize, heap: &Heap) -> usize {
+        let (addr, next) = self.read_constant(offset + 1, Opcode::from(self.code[offset]).is_long());
+        println!("{:16} {:4} {}", name, addr, self.constants[addr].display(heap));
+
+        let mut offset = next;
+        for _ in 0..self.closure_upvalue_count(addr, heap) {
+            let kind = if self.code[offset] == 1 { "local" } else { "upvalue" };
+            println!("{:04}    |                     {} {}", offset, kind, self.code[offset + 1]);
+            offset += 2;
+        }
+        offset
+    }
+
+    pub fn disassemble_instruction(&self, offset: usize, heap: &Heap) -> usize {
         print!("{:04} ", offset);
         if offset > 0 && self.line_at(offset) == self.line_at(offset - 1) {
             print!("   | ");
@@ -128,11 +358,7 @@ impl Chunk {
             print!("{:4} ", self.line_at(offset));
         }
         match Opcode::from(self.code[offset]) {
-            Opcode::Constant => {
-                let addr = self.code[offset + 1] as usize;
-                println!("{:16} {:4} '{}'", "OP_CONSTANT", addr, self.constants[addr]);
-                offset + 2
-            },
+            Opcode::Constant => self.constant_instruction("OP_CONSTANT", offset, heap),
             Opcode::Nil => { println!("OP_NIL"); offset + 1 },
             Opcode::True => { println!("OP_TRUE"); offset + 1 },
             Opcode::False => { println!("OP_FALSE"); offset + 1 },
@@ -145,11 +371,94 @@ impl Chunk {
             Opcode::Equal => { println!("OP_EQUAL"); offset + 1 },
             Opcode::Greater => { println!("OP_GREATER"); offset + 1 },
             Opcode::Less => { println!("OP_LESS"); offset + 1 },
+            Opcode::Print => { println!("OP_PRINT"); offset + 1 },
+            Opcode::Pop => { println!("OP_POP"); offset + 1 },
+            Opcode::DefineGlobal => self.constant_instruction("OP_DEFINE_GLOBAL", offset, heap),
+            Opcode::GetGlobal => self.constant_instruction("OP_GET_GLOBAL", offset, heap),
+            Opcode::SetGlobal => self.constant_instruction("OP_SET_GLOBAL", offset, heap),
+            Opcode::GetLocal => self.byte_instruction("OP_GET_LOCAL", offset),
+            Opcode::SetLocal => self.byte_instruction("OP_SET_LOCAL", offset),
+            Opcode::Jump => self.jump_instruction("OP_JUMP", true, offset),
+            Opcode::JumpIfFalse => self.jump_instruction("OP_JUMP_IF_FALSE", true, offset),
+            Opcode::Loop => self.jump_instruction("OP_LOOP", false, offset),
+            Opcode::Call => self.byte_instruction("OP_CALL", offset),
+            Opcode::Closure => self.closure_instruction("OP_CLOSURE", offset, heap),
+            Opcode::GetUpvalue => self.byte_instruction("OP_GET_UPVALUE", offset),
+            Opcode::SetUpvalue => self.byte_instruction("OP_SET_UPVALUE", offset),
+            Opcode::CloseUpvalue => { println!("OP_CLOSE_UPVALUE"); offset + 1 },
+            Opcode::Class => self.constant_instruction("OP_CLASS", offset, heap),
+            Opcode::GetProperty => self.constant_instruction("OP_GET_PROPERTY", offset, heap),
+            Opcode::SetProperty => self.constant_instruction("OP_SET_PROPERTY", offset, heap),
+            Opcode::Method => self.constant_instruction("OP_METHOD", offset, heap),
+            Opcode::Invoke => self.invoke_instruction("OP_INVOKE", offset, heap),
+            Opcode::Inherit => { println!("OP_INHERIT"); offset + 1 },
+            Opcode::GetSuper => self.constant_instruction("OP_GET_SUPER", offset, heap),
+            Opcode::SuperInvoke => self.invoke_instruction("OP_SUPER_INVOKE", offset, heap),
+            Opcode::ConstantLong => self.constant_instruction("OP_CONSTANT_LONG", offset, heap),
+            Opcode::DefineGlobalLong => self.constant_instruction("OP_DEFINE_GLOBAL_LONG", offset, heap),
+            Opcode::GetGlobalLong => self.constant_instruction("OP_GET_GLOBAL_LONG", offset, heap),
+            Opcode::SetGlobalLong => self.constant_instruction("OP_SET_GLOBAL_LONG", offset, heap),
+            Opcode::ClosureLong => self.closure_instruction("OP_CLOSURE_LONG", offset, heap),
+            Opcode::ClassLong => self.constant_instruction("OP_CLASS_LONG", offset, heap),
+            Opcode::GetPropertyLong => self.constant_instruction("OP_GET_PROPERTY_LONG", offset, heap),
+            Opcode::SetPropertyLong => self.constant_instruction("OP_SET_PROPERTY_LONG", offset, heap),
+            Opcode::MethodLong => self.constant_instruction("OP_METHOD_LONG", offset, heap),
+            Opcode::InvokeLong => self.invoke_instruction("OP_INVOKE_LONG", offset, heap),
+            Opcode::GetSuperLong => self.constant_instruction("OP_GET_SUPER_LONG", offset, heap),
+            Opcode::SuperInvokeLong => self.invoke_instruction("OP_SUPER_INVOKE_LONG", offset, heap),
+            Opcode::GreaterEqual => { println!("OP_GREATER_EQUAL"); offset + 1 },
+            Opcode::LessEqual => { println!("OP_LESS_EQUAL"); offset + 1 },
+            Opcode::NotEqual => { println!("OP_NOT_EQUAL"); offset + 1 },
             Opcode::Return => { println!("OP_RETURN"); offset + 1 },
             Opcode::Error => {
                 println!("INVALID OPCODE");
-                std::usize::MAX
+                usize::MAX
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{Chunk, Opcode, OPCODES};
+    use crate::heap::Heap;
+    use crate::value::Value;
+
+    #[test]
+    fn opcodes_decode_to_themselves() {
+        for &opcode in OPCODES.iter() {
+            assert_eq!(Opcode::from(u8::from(opcode)), opcode);
+        }
+        assert_eq!(Opcode::from(OPCODES.len() as u8), Opcode::Error);
+        assert_eq!(Opcode::from(u8::MAX), Opcode::Error);
+    }
+
+    #[test]
+    fn constants_are_deduplicated() {
+        let mut heap = Heap::default();
+        let mut chunk = Chunk::new();
+        let one = chunk.add_constant(Value::number(1.0));
+        assert_eq!(chunk.add_constant(Value::number(1.0)), one);
+
+        let zero = chunk.add_constant(Value::number(0.0));
+        let negative_zero = chunk.add_constant(Value::number(-0.0));
+        assert_ne!(zero, negative_zero);
+        assert_eq!(chunk.add_constant(Value::number(-0.0)), negative_zero);
+
+        let nan = chunk.add_constant(Value::number(f64::NAN));
+        assert_eq!(chunk.add_constant(Value::number(f64::NAN)), nan);
+
+        let a = chunk.add_constant(Value::obj(heap.intern("a")));
+        assert_eq!(chunk.add_constant(Value::obj(heap.intern("a"))), a);
+        assert_ne!(chunk.add_constant(Value::obj(heap.intern("b"))), a);
+        // Values of different types are never identical
+        assert_ne!(chunk.add_constant(Value::bool(false)), zero);
+        assert_eq!(chunk.constants.len(), 7);
+
+        // Moving the pool to another chunk keeps its entries findable
+        let mut moved = Chunk::new();
+        moved.take_constants(&mut chunk);
+        assert_eq!(moved.add_constant(Value::number(1.0)), one);
+        assert_eq!(moved.constants.len(), 7);
+    }
+}
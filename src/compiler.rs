@@ -1,373 +1,1180 @@
-use crate::chunk::{Chunk, Opcode};
-use crate::lexer::Lexer;
-use crate::token::{Token, TokenType};
-use crate::value::{Value, Obj};
-use crate::vm::{DEBUG, InterpretError};
-
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-enum Precedence {
-    None,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
-    Primary,
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::ast::{self, BinaryOp, Class, Expr, Identifier, LogicalOp, Program, Stmt, UnaryOp};
+use crate::chunk::{Chunk, Opcode, Source};
+use crate::heap::{Heap, Marker, ObjRef, Trace};
+use crate::optimizer;
+use crate::parser;
+use crate::token::Span;
+use crate::value::{Function, Value, Obj};
+use crate::vm::{Backend, DEBUG};
+
+// The token a compile error was reported at
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorToken {
+    Lexeme(String),
+    End,
+    Invalid, // The lexer couldn't make a token; the message says why
 }
 
-impl From<Precedence> for u8 {
-    fn from(prec: Precedence) -> u8 {
-        match prec {
-            Precedence::None       => 0,
-            Precedence::Assignment => 1,
-            Precedence::Or         => 2,
-            Precedence::And        => 3,
-            Precedence::Equality   => 4,
-            Precedence::Comparison => 5,
-            Precedence::Term       => 6,
-            Precedence::Factor     => 7,
-            Precedence::Unary      => 8,
-            Precedence::Call       => 9,
-            Precedence::Primary    => 10,
-        }
+// An error in a program's source, found while compiling it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub source: Rc<Source>,
+    pub span: Span,
+    pub token: ErrorToken,
+}
+
+impl Diagnostic {
+    // An error found after parsing, blamed on the token at `span`
+    pub(crate) fn at(message: &str, source: &Rc<Source>, span: Span) -> Diagnostic {
+        let token = match source.text.get(span.offset..span.offset + span.length) {
+            Some(lexeme) if !lexeme.is_empty() => ErrorToken::Lexeme(lexeme.to_owned()),
+            _ => ErrorToken::End,
+        };
+        Diagnostic { message: message.to_owned(), source: Rc::clone(source), span, token }
     }
 }
 
-impl From<u8> for Precedence {
-    fn from(n: u8) -> Precedence {
-        match n {
-            1 =>  Precedence::Assignment,
-            2 =>  Precedence::Or,
-            3 =>  Precedence::And,
-            4 =>  Precedence::Equality,
-            5 =>  Precedence::Comparison,
-            6 =>  Precedence::Term,
-            7 =>  Precedence::Factor,
-            8 =>  Precedence::Unary,
-            9 =>  Precedence::Call,
-            10 => Precedence::Primary,
-            _  => Precedence::None,
+// The most locals that can be addressed by a one-byte slot operand
+pub(crate) const LOCALS_MAX: usize = u8::MAX as usize + 1;
+
+// The most upvalues a closure can capture with one-byte operands
+pub(crate) const UPVALUES_MAX: usize = u8::MAX as usize + 1;
+
+pub(crate) struct Local<'a> {
+    pub(crate) name: &'a str,
+    // The scope depth the local was declared at,
+    // or None while its initializer is being compiled
+    pub(crate) depth: Option<usize>,
+    // Whether a closure captures this local, in which
+    // case it must be moved to the heap when it goes out of scope
+    pub(crate) is_captured: bool,
+}
+
+// A variable captured from an enclosing function
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct Upvalue {
+    pub(crate) index: u8,
+    // True if `index` is a local slot of the immediately enclosing
+    // function, false if it is one of that function's upvalues
+    pub(crate) is_local: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum FunctionType {
+    Function,
+    Initializer, // A class's `init` method
+    Method,
+    Script, // The implicit function wrapping top-level code
+}
+
+// A name the compiler refers to that doesn't appear in the source
+pub(crate) fn synthetic(name: &'static str) -> Identifier<'static> {
+    Identifier { name, span: Span::default() }
+}
+
+// Per-function compilation state
+struct Compiler<'a> {
+    function: Function,
+    function_type: FunctionType,
+    // Locals in scope, in stack slot order
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize, // 0 is global scope
+    // Set while the last code emitted is a lone constant load,
+    // so an operation on it can be folded at compile time
+    last_constant: Option<ConstantLoad>,
+}
+
+#[derive(Clone, Copy)]
+struct ConstantLoad {
+    start: usize, // The offset of the load instruction
+    pool_len: usize, // The size of the constant pool before the load
+    value: Value,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(function_type: FunctionType, name: Option<String>, source: Rc<Source>) -> Compiler<'a> {
+        let mut locals = Vec::with_capacity(LOCALS_MAX);
+        // Slot 0 holds the receiver in methods, so it's reachable as `this`.
+        // Otherwise it holds the function being called, and its empty
+        // name means user code can never refer to it
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        locals.push(Local {
+            name: slot_zero,
+            depth: Some(0),
+            is_captured: false,
+        });
+        Compiler {
+            function: Function { arity: 0, upvalue_count: 0, registers: 0, backend: Backend::Stack,
+                                 chunk: Chunk::with_source(source), name },
+            function_type,
+            locals,
+            upvalues: vec![],
+            scope_depth: 0,
+            last_constant: None,
         }
     }
 }
 
-impl Precedence {
-    fn plus_one(&self) -> Precedence {
-        (*self as u8 + 1).into()
-    }
+// Per-class compilation state
+pub(crate) struct ClassCompiler {
+    pub(crate) has_superclass: bool,
 }
 
-// Rules for a given TokenType
-struct ParseRule {
-    // The function to compile a prefix expression
-    // starting with a token of that type
-    prefix: Option<fn(&mut Parser)>,
-    // The function to compile an infix expression whose
-    // left operand is followed by a token of that type
-    infix: Option<fn(&mut Parser)>,
-    // The precedence of an infix expression
-    // that uses that token as an operator
-    precedence: Precedence,
+// Everything that must survive a collection during compilation: the
+// constants of every function still being compiled, and whatever the VM holds
+struct CompilerRoots<'r, 'a> {
+    compilers: &'r [Compiler<'a>],
+    vm: &'r dyn Trace,
 }
 
-fn get_parse_rule(token_type: TokenType) -> ParseRule {
-    match token_type {
-        TokenType::LeftParen => ParseRule {
-            prefix: Some(|parser| parser.grouping()),
-            infix: None,
-            precedence: Precedence::None,
-        },
-        TokenType::Bang => ParseRule {
-            prefix: Some(|parser| parser.unary()),
-            infix: None,
-            precedence: Precedence::Term,
-        },
-        TokenType::Minus => ParseRule {
-            prefix: Some(|parser| parser.unary()),
-            infix: Some(|parser| parser.binary()),
-            precedence: Precedence::Term,
-        },
-        TokenType::Plus => ParseRule {
-            prefix: None,
-            infix: Some(|parser| parser.binary()),
-            precedence: Precedence::Term,
-        },
-        TokenType::Slash => ParseRule {
-            prefix: None,
-            infix: Some(|parser| parser.binary()),
-            precedence: Precedence::Factor,
-        },
-        TokenType::Star => ParseRule {
-            prefix: None,
-            infix: Some(|parser| parser.binary()),
-            precedence: Precedence::Factor,
-        },
-        TokenType::EqualEqual => ParseRule {
-            prefix: None,
-            infix: Some(|parser| parser.binary()),
-            precedence: Precedence::Equality,
-        },
-        TokenType::BangEqual => ParseRule {
-            prefix: None,
-            infix: Some(|parser| parser.binary()),
-            precedence: Precedence::Equality,
-        },
-        TokenType::Greater => ParseRule {
-            prefix: None,
-            infix: Some(|parser| parser.binary()),
-            precedence: Precedence::Comparison,
-        },
-        TokenType::GreaterEqual => ParseRule {
-            prefix: None,
-            infix: Some(|parser| parser.binary()),
-            precedence: Precedence::Comparison,
-        },
-        TokenType::Less => ParseRule {
-            prefix: None,
-            infix: Some(|parser| parser.binary()),
-            precedence: Precedence::Comparison,
-        },
-        TokenType::LessEqual => ParseRule {
-            prefix: None,
-            infix: Some(|parser| parser.binary()),
-            precedence: Precedence::Comparison,
-        },
-        TokenType::String => ParseRule {
-            prefix: Some(|parser| parser.string()),
-            infix: None,
-            precedence: Precedence::None,
-        },
-        TokenType::Number => ParseRule {
-            prefix: Some(|parser| parser.number()),
-            infix: None,
-            precedence: Precedence::None,
-        },
-        TokenType::False => ParseRule {
-            prefix: Some(|parser| parser.literal()),
-            infix: None,
-            precedence: Precedence::None,
-        },
-        TokenType::True => ParseRule {
-            prefix: Some(|parser| parser.literal()),
-            infix: None,
-            precedence: Precedence::None,
-        },
-        TokenType::Nil => ParseRule {
-            prefix: Some(|parser| parser.literal()),
-            infix: None,
-            precedence: Precedence::None,
-        },
-        _ => ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: Precedence::None,
-        },
+impl Trace for CompilerRoots<'_, '_> {
+    fn trace(&self, marker: &mut Marker) {
+        for compiler in self.compilers {
+            compiler.function.trace(marker);
+        }
+        self.vm.trace(marker);
     }
 }
 
-pub struct Parser<'a> {
-    lexer: Lexer<'a>,
-    chunk: Chunk,
-    current: Token<'a>,
-    previous: Token<'a>,
-    had_error: bool,
-    panic_mode: bool, // Used for recoverable parsing
+// Lowers a program's syntax tree to bytecode. It walks the tree in source
+// order, keeping track of the last token it has passed, and blames
+// code that isn't tied to a particular token on that one
+pub struct CodeGenerator<'a> {
+    previous: Span,
+    source: Rc<Source>, // What's being compiled, for error reports
+    diagnostics: Vec<Diagnostic>, // Every error found so far
+    // Set after an error until the end of the statement,
+    // so one mistake is only reported once
+    panic_mode: bool,
+    // One compiler per function being compiled, innermost last
+    compilers: Vec<Compiler<'a>>,
+    // One per class being compiled, innermost last
+    class_compilers: Vec<ClassCompiler>,
+    heap: &'a mut Heap, // Where constants are allocated
+    roots: &'a dyn Trace, // The VM's roots, for collections during compilation
+    optimize: bool, // Whether to run the peephole optimizer over each function
 }
 
-impl<'a> Parser<'a> {
-    pub fn new(source: &'a str) -> Parser {
-        Parser {
-            lexer: Lexer::new(source),
-            chunk: Chunk::new(),
-            // TODO: Find a better pattern for this
-            // (what should current and previous be when they are not meaningful)
-            current: Token {
-                token_type: TokenType::Error,
-                lexeme: "",
-                line: 0,
-            },
-            previous: Token {
-                token_type: TokenType::Error,
-                lexeme: "",
-                line: 0,
-            },
-            had_error: false,
+impl<'a> CodeGenerator<'a> {
+    pub fn new(source: Rc<Source>, heap: &'a mut Heap, roots: &'a dyn Trace) -> CodeGenerator<'a> {
+        CodeGenerator {
+            previous: Span::default(),
+            source: Rc::clone(&source),
+            diagnostics: vec![],
             panic_mode: false,
+            compilers: vec![Compiler::new(FunctionType::Script, None, source)],
+            class_compilers: vec![],
+            heap,
+            roots,
+            optimize: false,
         }
     }
 
+    // Compile a program into the function that runs its top-level code
+    pub fn program(&mut self, program: &Program<'a>) -> Function {
+        for stmt in &program.statements {
+            self.declaration(stmt);
+        }
+        self.previous = program.end;
+        let (function, _) = self.end_compiler();
+        function
+    }
+
+    fn compiler(&mut self) -> &mut Compiler<'a> {
+        self.compilers.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler().function.chunk
+    }
+
+    fn error(&mut self, message: &str) {
+        if self.panic_mode { return; }
+        self.panic_mode = true;
+
+        let diagnostic = Diagnostic::at(message, &self.source, self.previous);
+        self.diagnostics.push(diagnostic);
+    }
+
     // ===================================
-    // Frontend (eating tokens)
+    // Variables
     // ===================================
-    fn advance(&mut self) {
-        self.previous = self.current;
-
-        // Read and report error tokens, stop when we hit a non-error
-        loop {
-            self.current = self.lexer.lex_token();
-            match self.current.token_type {
-                TokenType::Error => self.error_at_current(self.current.lexeme),
-                _ => break,
-            }
-        }
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let value = Value::obj(self.heap.intern(name));
+        self.add_constant(value)
     }
 
-    fn consume(&mut self, token_type: TokenType, message: &str) {
-        if self.current.token_type == token_type {
-            self.advance();
-        } else {
-            self.error_at_current(message);
+    // Find the stack slot of a local variable of the function
+    // compiled by `self.compilers[compiler]`, searching innermost first
+    fn resolve_local(&mut self, compiler: usize, name: &str) -> Option<u8> {
+        let (i, local) = self.compilers[compiler].locals.iter().enumerate().rev()
+            .find(|(_, local)| local.name == name)?;
+        let initialized = local.depth.is_some();
+        if !initialized {
+            self.error("Can't read local variable in its own initializer");
         }
+        Some(i as u8)
     }
 
-    fn error(&mut self, message: &str) {
-        self.error_at(self.previous, message)
+    fn add_upvalue(&mut self, compiler: usize, upvalue: Upvalue) -> u8 {
+        let upvalues = &self.compilers[compiler].upvalues;
+        // Closures capture each variable only once
+        if let Some(i) = upvalues.iter().position(|&existing| existing == upvalue) {
+            return i as u8;
+        }
+
+        if upvalues.len() == UPVALUES_MAX {
+            self.error("Too many closure variables in function");
+            return 0;
+        }
+
+        let compiler = &mut self.compilers[compiler];
+        compiler.upvalues.push(upvalue);
+        compiler.function.upvalue_count = compiler.upvalues.len();
+        (compiler.upvalues.len() - 1) as u8
     }
 
-    fn error_at_current(&mut self, message: &str) {
-        self.error_at(self.current, message)
+    // Find the upvalue index of a variable declared in a function
+    // enclosing the one compiled by `self.compilers[compiler]`,
+    // threading it through every function in between
+    fn resolve_upvalue(&mut self, compiler: usize, name: &str) -> Option<u8> {
+        if compiler == 0 {
+            return None;
+        }
+        let enclosing = compiler - 1;
+
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.compilers[enclosing].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(compiler, Upvalue { index: local, is_local: true }));
+        }
+
+        let upvalue = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(compiler, Upvalue { index: upvalue, is_local: false }))
     }
 
-    fn error_at(&mut self, token: Token, message: &str) {
-        if self.panic_mode { return; }
-        self.panic_mode = true;
+    // Get the variable `name`, or set it to `value` if there is one
+    fn named_variable(&mut self, name: Identifier, value: Option<&Expr<'a>>) {
+        let innermost = self.compilers.len() - 1;
+        // Only globals can be undefined at runtime, so only they
+        // need to point back at the name for error reports
+        let (get_op, set_op, arg, span) = if let Some(slot) = self.resolve_local(innermost, name.name) {
+            (Opcode::GetLocal, Opcode::SetLocal, slot as usize, None)
+        } else if let Some(index) = self.resolve_upvalue(innermost, name.name) {
+            (Opcode::GetUpvalue, Opcode::SetUpvalue, index as usize, None)
+        } else {
+            let arg = self.identifier_constant(name.name);
+            (Opcode::GetGlobal, Opcode::SetGlobal, arg, Some(name.span))
+        };
 
-        eprint!("[line {}] Error", self.current.line);
-        let loc = match token.token_type {
-            TokenType::EOF => " at end".to_owned(),
-            TokenType::Error => "".to_owned(),
-            _ => format!(" at '{}'", token.lexeme),
+        let op = match value {
+            Some(value) => {
+                self.expression(value);
+                set_op
+            },
+            None => get_op,
         };
-        eprintln!("{}: {}", loc, message);
+        let span = span.unwrap_or(self.previous);
+        self.emit_with_operand_at(op, arg, span);
+    }
+
+    fn variable(&mut self, name: Identifier, value: Option<&Expr<'a>>) {
+        self.previous = name.span;
+        self.named_variable(name, value);
+    }
 
-        self.had_error = true;
+    fn add_local(&mut self, name: &'a str) {
+        if self.compiler().locals.len() == LOCALS_MAX {
+            self.error("Too many local variables in function");
+            return;
+        }
+        self.compiler().locals.push(Local { name, depth: None, is_captured: false });
+    }
+
+    // Record a new local in the current scope. Globals are late bound,
+    // so there is nothing to record for them
+    fn declare_variable(&mut self, name: &'a str) {
+        if self.compiler().scope_depth == 0 {
+            return;
+        }
+
+        let scope_depth = self.compiler().scope_depth;
+        let already_declared = self.compiler().locals.iter().rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == name);
+        if already_declared {
+            self.error("Already a variable with this name in this scope");
+        }
+
+        self.add_local(name);
+    }
+
+    // Declare a variable and return the constant index of its name,
+    // or 0 for a local, which needs no name at runtime
+    fn parse_variable(&mut self, name: Identifier<'a>) -> usize {
+        self.previous = name.span;
+
+        self.declare_variable(name.name);
+        if self.compiler().scope_depth > 0 {
+            return 0;
+        }
+
+        self.identifier_constant(name.name)
+    }
+
+    fn mark_initialized(&mut self) {
+        let compiler = self.compiler();
+        if compiler.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = compiler.locals.last_mut() {
+            local.depth = Some(compiler.scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        // A local's value is already in its stack slot
+        if self.compiler().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_with_operand(Opcode::DefineGlobal, global);
     }
 
     // ===================================
-    // Backend (bytecode gen)
+    // Expressions
     // ===================================
-    fn number(&mut self) {
-        // TODO: handle parse error
-        let n = self.previous.lexeme.parse::<f64>().unwrap();
-        self.emit_constant(Value::Number(n));
-    }
-
-    fn string(&mut self) {
-        match self.previous.token_type {
-            TokenType::String => {
-                // Trim outer quotes
-                let trimmed = self.previous.lexeme[1..(self.previous.lexeme.len()-1)].to_owned();
-                self.emit_constant(Value::Obj(Box::new(Obj::String(trimmed))))
+    fn expression(&mut self, expr: &Expr<'a>) {
+        match expr {
+            Expr::Number { value, span } => {
+                self.previous = *span;
+                self.emit_constant(Value::number(*value));
+            },
+            Expr::String { value, span } => {
+                self.previous = *span;
+                let interned = self.heap.intern(value);
+                self.emit_constant(Value::obj(interned));
+            },
+            Expr::Bool { value, span } => {
+                self.previous = *span;
+                self.emit_constant(Value::bool(*value));
+            },
+            Expr::Nil { span } => {
+                self.previous = *span;
+                self.emit_constant(Value::NIL);
+            },
+            Expr::Variable(name) => self.variable(*name, None),
+            Expr::Assign { name, value } => self.variable(*name, Some(value)),
+            Expr::This { span } => self.this(*span),
+            Expr::Super { keyword, method } => self.super_(*keyword, *method, None),
+            Expr::Unary { op, op_span, operand } => self.unary(*op, *op_span, operand),
+            Expr::Binary { .. } => self.binary(expr),
+            Expr::Logical { op: LogicalOp::And, op_span, left, right } => self.and(*op_span, left, right),
+            Expr::Logical { op: LogicalOp::Or, op_span, left, right } => self.or(*op_span, left, right),
+            Expr::Grouping { expr, right_paren, .. } => {
+                self.expression(expr);
+                self.previous = *right_paren;
+            },
+            Expr::Call { callee, arguments, right_paren } => self.call(callee, arguments, *right_paren),
+            Expr::Get { object, name } => {
+                self.expression(object);
+                self.previous = name.span;
+                let name_constant = self.identifier_constant(name.name);
+                self.emit_with_operand_at(Opcode::GetProperty, name_constant, name.span);
+            },
+            Expr::Set { object, name, value } => {
+                self.expression(object);
+                self.previous = name.span;
+                let name_constant = self.identifier_constant(name.name);
+                self.expression(value);
+                self.emit_with_operand_at(Opcode::SetProperty, name_constant, name.span);
+            },
+        }
+    }
+
+    fn arguments(&mut self, arguments: &[Expr<'a>]) -> u8 {
+        for argument in arguments {
+            self.expression(argument);
+        }
+        arguments.len() as u8
+    }
+
+    fn call(&mut self, callee: &Expr<'a>, arguments: &[Expr<'a>], right_paren: Span) {
+        match callee {
+            // Call the method directly instead of creating a bound method
+            Expr::Get { object, name } => {
+                self.expression(object);
+                self.previous = name.span;
+                let name_constant = self.identifier_constant(name.name);
+                let arg_count = self.arguments(arguments);
+                self.previous = right_paren;
+                self.emit_with_operand_at(Opcode::Invoke, name_constant, name.span);
+                self.emit_byte_at(arg_count, name.span);
+            },
+            Expr::Super { keyword, method } => self.super_(*keyword, *method, Some((arguments, right_paren))),
+            _ => {
+                self.expression(callee);
+                let arg_count = self.arguments(arguments);
+                self.previous = right_paren;
+                self.emit_bytes(Opcode::Call.into(), arg_count);
+            },
+        }
+    }
+
+    // `super.method`, or a call to it with `call`'s arguments
+    fn super_(&mut self, keyword: Span, method: Identifier, call: Option<(&[Expr<'a>], Span)>) {
+        self.previous = keyword;
+        match self.class_compilers.last() {
+            None => self.error("Can't use 'super' outside of a class"),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass");
             },
-            _ => (), // Should be unreachable
+            _ => (),
+        }
+
+        self.previous = method.span;
+        let name = self.identifier_constant(method.name);
+
+        // The method is looked up on the superclass statically, but bound to `this`
+        self.named_variable(synthetic("this"), None);
+        if let Some((arguments, right_paren)) = call {
+            let arg_count = self.arguments(arguments);
+            self.previous = right_paren;
+            self.named_variable(synthetic("super"), None);
+            self.emit_with_operand_at(Opcode::SuperInvoke, name, method.span);
+            self.emit_byte_at(arg_count, method.span);
+        } else {
+            self.named_variable(synthetic("super"), None);
+            self.emit_with_operand_at(Opcode::GetSuper, name, method.span);
         }
     }
 
-    fn literal(&mut self) {
-        match self.previous.token_type {
-            TokenType::False => self.emit_constant(Value::Bool(false)),
-            TokenType::True => self.emit_constant(Value::Bool(true)),
-            TokenType::Nil => self.emit_constant(Value::Nil),
-            _ => (), // Should be unreachable
+    fn this(&mut self, span: Span) {
+        self.previous = span;
+        if self.class_compilers.is_empty() {
+            self.error("Can't use 'this' outside of a class");
+            return;
         }
+        self.named_variable(Identifier { name: "this", span }, None);
     }
 
-    fn grouping(&mut self) {
-        self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after expression");
+    fn unary(&mut self, op: UnaryOp, op_span: Span, operand: &Expr<'a>) {
+        let start = self.chunk().code.len();
+        self.expression(operand);
+
+        if let Some(operand) = self.constant_since(start) {
+            if let Some(value) = fold_unary(op, operand.value) {
+                self.chunk().truncate(start);
+                self.chunk().truncate_constants(operand.pool_len);
+                self.emit_constant(value);
+                return;
+            }
+        }
+
+        // Runtime errors point at the operator, not the operand
+        let opcode = match op {
+            UnaryOp::Negate => Opcode::Neg,
+            UnaryOp::Not => Opcode::Not,
+        };
+        self.emit_byte_at(opcode.into(), op_span);
     }
 
-    fn unary(&mut self) {
-        let op_type = self.previous.token_type;
-        self.parse_precedence(Precedence::Unary);
+    fn binary(&mut self, expr: &Expr<'a>) {
+        // Chains like `a + b + c` nest to the left. Walk down them
+        // iteratively so long ones don't need a stack frame per operator
+        let mut operations = vec![];
+        let mut left = expr;
+        while let Expr::Binary { op, op_span, left: inner, right } = left {
+            operations.push((*op, *op_span, &**right));
+            left = inner;
+        }
+        self.expression(left);
+        for (op, op_span, right) in operations.into_iter().rev() {
+            self.binary_operation(op, op_span, right);
+        }
+    }
+
+    // Apply `op` to the value on the stack and `right`
+    fn binary_operation(&mut self, op: BinaryOp, op_span: Span, right: &Expr<'a>) {
+        // Nothing has been emitted since the left operand
+        let left = self.compiler().last_constant;
+        let start = self.chunk().code.len();
+        self.expression(right);
+
+        if let (Some(left), Some(right)) = (left, self.constant_since(start)) {
+            if let Some(value) = fold_binary(self.heap, op, left.value, right.value) {
+                // The operands' constants go along with their loads
+                self.chunk().truncate(left.start);
+                self.chunk().truncate_constants(left.pool_len);
+                self.emit_constant(value);
+                return;
+            }
+        }
 
-        match op_type {
-            TokenType::Minus => self.emit_byte(Opcode::Neg.into()),
-            TokenType::Bang => self.emit_byte(Opcode::Not.into()),
-            _ => (), // TODO: Should be unreachable
+        // Runtime errors point at the operator, not the right operand
+        let opcode = match op {
+            BinaryOp::Add => Opcode::Add,
+            BinaryOp::Sub => Opcode::Sub,
+            BinaryOp::Mul => Opcode::Mul,
+            BinaryOp::Div => Opcode::Div,
+            BinaryOp::Equal => Opcode::Equal,
+            BinaryOp::NotEqual => Opcode::NotEqual,
+            BinaryOp::Greater => Opcode::Greater,
+            BinaryOp::GreaterEqual => Opcode::GreaterEqual,
+            BinaryOp::Less => Opcode::Less,
+            BinaryOp::LessEqual => Opcode::LessEqual,
         };
+        self.emit_byte_at(opcode.into(), op_span);
+    }
+
+    // The constant that the code emitted from `start` onwards loads, if
+    // that's all it does. Only then can the operation using it be folded
+    fn constant_since(&mut self, start: usize) -> Option<ConstantLoad> {
+        self.compiler().last_constant.filter(|load| load.start == start)
     }
 
-    fn binary(&mut self) {
-        let op_type = self.previous.token_type;
+    // Short-circuit: if the left operand is falsey it is the result
+    fn and(&mut self, op_span: Span, left: &Expr<'a>, right: &Expr<'a>) {
+        self.expression(left);
+        self.previous = op_span;
+        let end_jump = self.emit_jump(Opcode::JumpIfFalse);
 
-        let rule = get_parse_rule(op_type);
-        self.parse_precedence(rule.precedence.plus_one());
-        // TODO: error, no rule for token '${op_type}' as a bin operator
-        // if it's a default ParseRule?
+        self.emit_byte(Opcode::Pop.into());
+        self.expression(right);
 
-        match op_type {
-            TokenType::Plus => self.emit_byte(Opcode::Add.into()),
-            TokenType::Minus => self.emit_byte(Opcode::Sub.into()),
-            TokenType::Star => self.emit_byte(Opcode::Mul.into()),
-            TokenType::Slash => self.emit_byte(Opcode::Div.into()),
-            TokenType::EqualEqual => self.emit_byte(Opcode::Equal.into()),
-            TokenType::BangEqual => { self.emit_byte(Opcode::Equal.into()); self.emit_byte(Opcode::Not.into()); },
-            TokenType::Greater => self.emit_byte(Opcode::Greater.into()),
-            TokenType::GreaterEqual => { self.emit_byte(Opcode::Less.into()); self.emit_byte(Opcode::Not.into()); },
-            TokenType::Less => self.emit_byte(Opcode::Less.into()),
-            TokenType::LessEqual => { self.emit_byte(Opcode::Greater.into()); self.emit_byte(Opcode::Not.into()); },
-            _ => (), // TODO: Should never happen
+        self.patch_jump(end_jump);
+    }
+
+    // Short-circuit: if the left operand is truthy it is the result
+    fn or(&mut self, op_span: Span, left: &Expr<'a>, right: &Expr<'a>) {
+        self.expression(left);
+        self.previous = op_span;
+        let else_jump = self.emit_jump(Opcode::JumpIfFalse);
+        let end_jump = self.emit_jump(Opcode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(Opcode::Pop.into());
+
+        self.expression(right);
+        self.patch_jump(end_jump);
+    }
+
+    // ===================================
+    // Statements
+    // ===================================
+    // A statement in a block, function body, or at the top level
+    fn declaration(&mut self, stmt: &Stmt<'a>) {
+        self.statement(stmt);
+        self.panic_mode = false;
+    }
+
+    fn statement(&mut self, stmt: &Stmt<'a>) {
+        match stmt {
+            Stmt::Expression { expr, semicolon } => {
+                self.expression(expr);
+                self.previous = *semicolon;
+                self.emit_byte(Opcode::Pop.into());
+            },
+            Stmt::Print { expr, semicolon, .. } => {
+                self.expression(expr);
+                self.previous = *semicolon;
+                self.emit_byte(Opcode::Print.into());
+            },
+            Stmt::Var { name, initializer, semicolon } => {
+                self.var_declaration(*name, initializer.as_ref(), *semicolon);
+            },
+            Stmt::Function(function) => self.fun_declaration(function),
+            Stmt::Class(class) => self.class_declaration(class),
+            Stmt::Block { statements, right_brace, .. } => {
+                self.begin_scope();
+                for stmt in statements {
+                    self.declaration(stmt);
+                }
+                self.previous = *right_brace;
+                self.end_scope();
+            },
+            Stmt::If { condition, right_paren, then_branch, else_branch, .. } => {
+                self.if_statement(condition, *right_paren, then_branch, else_branch.as_deref());
+            },
+            Stmt::While { condition, right_paren, body, .. } => {
+                self.while_statement(condition, *right_paren, body);
+            },
+            Stmt::For { initializer, condition, semicolon, increment, right_paren, body, .. } => {
+                self.for_statement(initializer.as_deref(), condition.as_ref(), *semicolon,
+                                   increment.as_ref(), *right_paren, body);
+            },
+            Stmt::Return { keyword, value, semicolon } => {
+                self.return_statement(*keyword, value.as_ref(), *semicolon);
+            },
         }
     }
 
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
+    fn class_declaration(&mut self, class: &Class<'a>) {
+        self.previous = class.name.span;
+        let name_constant = self.identifier_constant(class.name.name);
+        self.declare_variable(class.name.name);
+
+        self.emit_with_operand(Opcode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.class_compilers.push(ClassCompiler { has_superclass: false });
+
+        if let Some(superclass) = class.superclass {
+            self.variable(superclass, None);
+
+            if class.name.name == superclass.name {
+                self.error("A class can't inherit from itself");
+            }
+
+            // Methods find the superclass in a local named `super`, in a
+            // scope of its own so each subclass captures its own superclass
+            self.begin_scope();
+            self.add_local("super");
+            self.define_variable(0);
+
+            self.named_variable(class.name, None);
+            self.emit_byte(Opcode::Inherit.into());
+            self.class_compilers.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Keep the class on the stack while its methods are bound to it
+        self.named_variable(class.name, None);
+        for method in &class.methods {
+            self.method(method);
+        }
+        self.previous = class.right_brace;
+        self.emit_byte(Opcode::Pop.into());
+
+        if self.class_compilers.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
     }
 
-    fn parse_precedence(&mut self, prec: Precedence) {
-        self.advance();
-        let prefix_rule = get_parse_rule(self.previous.token_type);
-        if let Some(prefix_fn) = prefix_rule.prefix {
-            prefix_fn(self);
+    fn method(&mut self, method: &ast::Function<'a>) {
+        self.previous = method.name.span;
+        let name_constant = self.identifier_constant(method.name.name);
+
+        let function_type = if method.name.name == "init" {
+            FunctionType::Initializer
         } else {
-            self.error("Expect expression");
-            return;
+            FunctionType::Method
+        };
+        self.function(method, function_type);
+
+        self.emit_with_operand(Opcode::Method, name_constant);
+    }
+
+    fn fun_declaration(&mut self, function: &ast::Function<'a>) {
+        let global = self.parse_variable(function.name);
+        // A function may refer to itself, so it's initialized before its body
+        self.mark_initialized();
+        self.function(function, FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    // Compile a function's parameters and body, leaving the function on the stack
+    fn function(&mut self, function: &ast::Function<'a>, function_type: FunctionType) {
+        let name = function.name.name.to_owned();
+        let source = Rc::clone(&self.source);
+        self.compilers.push(Compiler::new(function_type, Some(name), source));
+        // The function's body scope is never ended; its locals are
+        // discarded along with the call frame when it returns
+        self.begin_scope();
+
+        for &param in &function.params {
+            self.compiler().function.arity += 1;
+            let constant = self.parse_variable(param);
+            self.define_variable(constant);
+        }
+        for stmt in &function.body {
+            self.declaration(stmt);
+        }
+        self.previous = function.right_brace;
+
+        let (function, upvalues) = self.end_compiler();
+        let function = self.heap.alloc(Obj::Function(Rc::new(function)));
+        let constant = self.add_constant(Value::obj(function));
+        self.emit_with_operand(Opcode::Closure, constant);
+
+        // Tell the VM where to capture each upvalue from
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
         }
+    }
+
+    fn var_declaration(&mut self, name: Identifier<'a>, initializer: Option<&Expr<'a>>, semicolon: Span) {
+        let global = self.parse_variable(name);
+
+        match initializer {
+            Some(initializer) => self.expression(initializer),
+            None => self.emit_byte(Opcode::Nil.into()),
+        }
+        self.previous = semicolon;
+
+        self.define_variable(global);
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler().scope_depth -= 1;
 
-        while prec <= get_parse_rule(self.current.token_type).precedence {
-            self.advance();
-            let infix_rule = get_parse_rule(self.previous.token_type);
-            if let Some(infix_fn) = infix_rule.infix {
-                infix_fn(self);
+        // Pop the locals that belonged to the scope we're leaving,
+        // moving any that closures captured onto the heap
+        let scope_depth = self.compiler().scope_depth;
+        while let Some(local) = self.compiler().locals.last() {
+            if local.depth.is_some_and(|depth| depth <= scope_depth) {
+                break;
+            }
+            if local.is_captured {
+                self.emit_byte(Opcode::CloseUpvalue.into());
             } else {
-                // TODO: is this the error i want
-                self.error("Expect expression");
-                return;
+                self.emit_byte(Opcode::Pop.into());
             }
+            self.compiler().locals.pop();
+        }
+    }
+
+    fn if_statement(&mut self, condition: &Expr<'a>, right_paren: Span,
+                    then_branch: &Stmt<'a>, else_branch: Option<&Stmt<'a>>) {
+        self.expression(condition);
+        self.previous = right_paren;
+
+        // The condition stays on the stack, so each branch pops it first
+        let then_jump = self.emit_jump(Opcode::JumpIfFalse);
+        self.emit_byte(Opcode::Pop.into());
+        self.statement(then_branch);
+
+        let else_jump = self.emit_jump(Opcode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(Opcode::Pop.into());
+
+        if let Some(else_branch) = else_branch {
+            self.statement(else_branch);
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self, condition: &Expr<'a>, right_paren: Span, body: &Stmt<'a>) {
+        let loop_start = self.chunk().code.len();
+        self.expression(condition);
+        self.previous = right_paren;
+
+        let exit_jump = self.emit_jump(Opcode::JumpIfFalse);
+        self.emit_byte(Opcode::Pop.into());
+        self.statement(body);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(Opcode::Pop.into());
+    }
+
+    fn for_statement(&mut self, initializer: Option<&Stmt<'a>>, condition: Option<&Expr<'a>>, semicolon: Span,
+                     increment: Option<&Expr<'a>>, right_paren: Span, body: &Stmt<'a>) {
+        // Variables declared in the initializer are scoped to the loop
+        self.begin_scope();
+        let mut loop_variable = None;
+        match initializer {
+            None => (),
+            Some(Stmt::Var { name, initializer, semicolon }) => {
+                self.var_declaration(*name, initializer.as_ref(), *semicolon);
+                let slot = self.compiler().locals.len() - 1;
+                loop_variable = Some((slot as u8, self.compiler().locals[slot].name));
+            },
+            Some(initializer) => self.statement(initializer),
+        }
+
+        let mut loop_start = self.chunk().code.len();
+        let mut exit_jump = None;
+        if let Some(condition) = condition {
+            self.expression(condition);
+            self.previous = semicolon;
+
+            exit_jump = Some(self.emit_jump(Opcode::JumpIfFalse));
+            self.emit_byte(Opcode::Pop.into());
+        }
+
+        // The increment is compiled before the body but runs after it,
+        // so jump over it now and loop back to it from the end of the body
+        if let Some(increment) = increment {
+            self.previous = semicolon;
+            let body_jump = self.emit_jump(Opcode::Jump);
+            let increment_start = self.chunk().code.len();
+            self.expression(increment);
+            self.emit_byte(Opcode::Pop.into());
+            self.previous = right_paren;
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.previous = right_paren;
+        match loop_variable {
+            Some((slot, name)) => self.loop_body_with_copy(slot, name, body),
+            None => self.statement(body),
+        }
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(Opcode::Pop.into());
+        }
+
+        self.end_scope();
+    }
+
+    // Compile a `for` body that sees a fresh copy of the loop variable, so
+    // closures created in different iterations capture different variables
+    fn loop_body_with_copy(&mut self, slot: u8, name: &'a str, body: &Stmt<'a>) {
+        self.begin_scope();
+        self.emit_bytes(Opcode::GetLocal.into(), slot);
+        self.add_local(name);
+        self.mark_initialized();
+        let copy = (self.compiler().locals.len() - 1) as u8;
+
+        self.statement(body);
+
+        // Write the copy back so the increment clause sees body assignments
+        self.emit_bytes(Opcode::GetLocal.into(), copy);
+        self.emit_bytes(Opcode::SetLocal.into(), slot);
+        self.emit_byte(Opcode::Pop.into());
+        self.end_scope();
+    }
+
+    fn return_statement(&mut self, keyword: Span, value: Option<&Expr<'a>>, semicolon: Span) {
+        self.previous = keyword;
+        if self.compiler().function_type == FunctionType::Script {
+            self.error("Can't return from top-level code");
+        }
+
+        match value {
+            None => {
+                self.previous = semicolon;
+                self.emit_return();
+            },
+            Some(value) => {
+                if self.compiler().function_type == FunctionType::Initializer {
+                    self.error("Can't return a value from an initializer");
+                }
+
+                self.expression(value);
+                self.previous = semicolon;
+                self.emit_byte(Opcode::Return.into());
+            },
         }
     }
 
+    // ===================================
+    // Emitting code
+    // ===================================
     fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write(byte, self.previous.line);
+        let span = self.previous;
+        self.emit_byte_at(byte, span);
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
+    }
+
+    // Emit code that runtime errors should blame on `span`
+    // rather than on the last token passed
+    fn emit_byte_at(&mut self, byte: u8, span: Span) {
+        self.compiler().last_constant = None;
+        self.chunk().write(byte, span);
+    }
+
+    fn emit_bytes_at(&mut self, byte1: u8, byte2: u8, span: Span) {
+        self.emit_byte_at(byte1, span);
+        self.emit_byte_at(byte2, span);
+    }
+
+    fn emit_with_operand(&mut self, opcode: Opcode, operand: usize) {
+        let span = self.previous;
+        self.emit_with_operand_at(opcode, operand, span);
+    }
+
+    // Emit an instruction with a one-byte operand, or its long form if the
+    // operand needs more. Only constant operands can get that big.
+    // Returns whether there was room for the operand
+    fn emit_with_operand_at(&mut self, opcode: Opcode, operand: usize, span: Span) -> bool {
+        if let Ok(operand) = u8::try_from(operand) {
+            self.emit_bytes_at(opcode.into(), operand, span);
+        } else if operand < 1 << 24 {
+            self.emit_bytes_at(opcode.long().into(), (operand >> 16) as u8, span);
+            self.emit_bytes_at((operand >> 8) as u8, operand as u8, span);
+        } else {
+            self.error("Too many constants in one chunk");
+            return false;
+        }
+        true
+    }
+
+    // Functions without an explicit return value return nil,
+    // except initializers, which return the new instance
+    fn emit_return(&mut self) {
+        if self.compiler().function_type == FunctionType::Initializer {
+            self.emit_bytes(Opcode::GetLocal.into(), 0);
+        } else {
+            self.emit_byte(Opcode::Nil.into());
+        }
+        self.emit_byte(Opcode::Return.into());
+    }
+
+    // Finish the innermost function and return it with its upvalues
+    fn end_compiler(&mut self) -> (Function, Vec<Upvalue>) {
+        self.emit_return();
+        let mut compiler = self.compilers.pop().unwrap();
+
+        // Code with errors is never run, and may not even be well-formed
+        if self.optimize && self.diagnostics.is_empty() {
+            optimizer::optimize(&mut compiler.function.chunk, self.heap);
+        }
+
+        if DEBUG && self.diagnostics.is_empty() {
+            let name = compiler.function.name.as_deref().unwrap_or("<script>");
+            compiler.function.chunk.disassemble(name, self.heap);
+        }
+
+        (compiler.function, compiler.upvalues)
+    }
+
+    // Emit a jump with a placeholder offset, returning the
+    // location of the offset so it can be patched later
+    fn emit_jump(&mut self, op: Opcode) -> usize {
+        self.emit_byte(op.into());
+        self.emit_bytes(0xff, 0xff);
+        self.chunk().code.len() - 2
+    }
+
+    // Point the jump whose offset is at `offset` to the next instruction
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump offset itself
+        let jump = self.chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over");
+        }
+
+        self.chunk().code[offset] = (jump >> 8) as u8;
+        self.chunk().code[offset + 1] = jump as u8;
+        // Control flow joins here, so what came before isn't a lone constant
+        self.compiler().last_constant = None;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(Opcode::Loop.into());
+
+        // +2 to jump back over the Loop operand too
+        let offset = self.chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large");
+        }
+
+        self.emit_bytes((offset >> 8) as u8, offset as u8);
+    }
+
+    fn add_constant(&mut self, value: Value) -> usize {
+        let i = self.chunk().add_constant(value);
+
+        // Constants are only allocated just before being added here,
+        // so this is the first point at which they are all reachable
+        if self.heap.should_collect() {
+            self.heap.collect(&CompilerRoots { compilers: &self.compilers, vm: self.roots });
+        }
+
+        i
     }
 
     fn emit_constant(&mut self, value: Value) {
-        self.emit_byte(Opcode::Constant.into());
-        let i = self.chunk.add_constant(value);
-        self.emit_byte(i as u8);
+        let start = self.chunk().code.len();
+        let pool_len = self.chunk().constants.len();
+        let i = self.add_constant(value);
+        let span = self.previous;
+        if self.emit_with_operand_at(Opcode::Constant, i, span) {
+            self.compiler().last_constant = Some(ConstantLoad { start, pool_len, value });
+        }
     }
 }
 
-pub fn compile(source: &str) -> Result<Chunk, InterpretError> {
-    let mut parser = Parser::new(source);
-
-    parser.advance();
-    parser.expression();
-    parser.consume(TokenType::EOF, "Expect end of expression");
-    parser.emit_byte(Opcode::Return.into());
+// Evaluate a binary operation on constants at compile time, exactly as the VM
+// would. Operations the VM would report an error for aren't folded,
+// so they still fail at runtime with the same message
+pub(crate) fn fold_binary(heap: &mut Heap, op: BinaryOp, lhs: Value, rhs: Value) -> Option<Value> {
+    let numbers = lhs.as_number().zip(rhs.as_number());
+    let value = match op {
+        BinaryOp::Add => match numbers {
+            Some((a, b)) => Value::number(a + b),
+            None => {
+                let (a, b) = (as_string(heap, lhs)?, as_string(heap, rhs)?);
+                let concat = heap.string(a).to_owned() + heap.string(b);
+                Value::obj(heap.intern(&concat))
+            },
+        },
+        BinaryOp::Sub => numbers.map(|(a, b)| Value::number(a - b))?,
+        BinaryOp::Mul => numbers.map(|(a, b)| Value::number(a * b))?,
+        BinaryOp::Div => numbers.map(|(a, b)| Value::number(a / b))?,
+        BinaryOp::Equal => Value::bool(lhs == rhs),
+        BinaryOp::NotEqual => Value::bool(lhs != rhs),
+        BinaryOp::Greater => numbers.map(|(a, b)| Value::bool(a > b))?,
+        BinaryOp::Less => numbers.map(|(a, b)| Value::bool(a < b))?,
+        BinaryOp::GreaterEqual => numbers.map(|(a, b)| Value::bool(a >= b))?,
+        BinaryOp::LessEqual => numbers.map(|(a, b)| Value::bool(a <= b))?,
+    };
+    Some(value)
+}
 
-    if DEBUG && !parser.had_error {
-        parser.chunk.disassemble("code");
+pub(crate) fn fold_unary(op: UnaryOp, operand: Value) -> Option<Value> {
+    match op {
+        UnaryOp::Negate => operand.as_number().map(|n| Value::number(-n)),
+        UnaryOp::Not => Some(Value::bool(operand.is_falsey())),
     }
+}
+
+fn as_string(heap: &Heap, value: Value) -> Option<ObjRef> {
+    value.as_obj().filter(|&obj| matches!(heap.get(obj), Obj::String(_)))
+}
+
+// Compile a program into the function that runs its top-level code,
+// allocating its constants in `heap`. Collections during compilation
+// keep everything reachable from `roots` alive
+pub fn compile(source: &str, source_name: &str, heap: &mut Heap, roots: &dyn Trace, optimize: bool
+) -> Result<Function, Vec<Diagnostic>>
+{
+    let source = Rc::new(Source { name: source_name.to_owned(), text: source.to_owned() });
+    let (program, diagnostics) = parser::parse(&source);
+
+    // Statements with syntax errors were left out of the tree, but the
+    // rest are still compiled to report the errors only compiling finds
+    let mut generator = CodeGenerator::new(Rc::clone(&source), heap, roots);
+    generator.diagnostics = diagnostics;
+    generator.optimize = optimize;
+    let function = generator.program(&program);
 
-    if parser.had_error {
-        Err(InterpretError::CompileError)
+    let mut diagnostics = generator.diagnostics;
+    if diagnostics.is_empty() {
+        Ok(function)
     } else {
-        Ok(parser.chunk)
+        diagnostics.sort_by_key(|diagnostic| diagnostic.span.offset);
+        Err(diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::Opcode;
+    use crate::compiler::compile;
+    use crate::heap::Heap;
+    use crate::value::{Function, Obj, Value};
+
+    fn compile_script(source: &str, heap: &mut Heap) -> Function {
+        compile_with(source, heap, false)
+    }
+
+    fn compile_with(source: &str, heap: &mut Heap, optimize: bool) -> Function {
+        let roots: [Value; 0] = [];
+        compile(source, "test.lox", heap, &roots, optimize).unwrap_or_else(|_| panic!("{} should compile", source))
+    }
+
+    // The one constant a folded `print` statement prints
+    fn folded(source: &str, heap: &mut Heap) -> Value {
+        let function = compile_script(source, heap);
+        let code = &function.chunk.code;
+        let expected: Vec<u8> = vec![Opcode::Constant.into(), code[1], Opcode::Print.into(),
+                                     Opcode::Nil.into(), Opcode::Return.into()];
+        assert_eq!(*code, expected, "{} should be folded", source);
+        function.chunk.constants[code[1] as usize]
+    }
+
+    fn contains(source: &str, op: Opcode) -> bool {
+        compile_script(source, &mut Heap::default()).chunk.code.contains(&op.into())
+    }
+
+    #[test]
+    fn constant_operations_are_folded() {
+        let mut heap = Heap::default();
+        assert_eq!(folded("print -(2 * 3) + 4;", &mut heap), Value::number(-2.0));
+        assert_eq!(folded("print 1 / 0 > 10 == !nil;", &mut heap), Value::bool(true));
+        // Comparisons with NaN fold to what the VM computes
+        assert_eq!(folded("print 0 / 0 >= 0;", &mut heap), Value::bool(false));
+        assert_eq!(folded("print 0 / 0 == 0 / 0;", &mut heap), Value::bool(false));
+
+        let ab = folded("print \"a\" + \"b\";", &mut heap);
+        assert!(ab == Value::obj(heap.intern("ab")));
+        assert_eq!(folded("print \"a\" + \"b\" != \"ab\";", &mut heap), Value::bool(false));
+    }
+
+    #[test]
+    fn folded_operands_leave_the_pool() {
+        let mut heap = Heap::default();
+        let script = compile_script("print -(2 * 3) + 4; print (\"a\" + \"b\") + \"c\";", &mut heap);
+        let abc = Value::obj(heap.intern("abc"));
+        assert!(script.chunk.constants == vec![Value::number(-2.0), abc]);
+        // A constant used before the operation stays
+        let script = compile_script("print 2; print 2 * 3;", &mut heap);
+        assert!(script.chunk.constants == vec![Value::number(2.0), Value::number(6.0)]);
+    }
+
+    #[test]
+    fn only_constant_operations_are_folded() {
+        assert!(contains("var a = 1; print a + 1;", Opcode::Add));
+        assert!(contains("var a = 1; print 1 + a;", Opcode::Add));
+        assert!(contains("var a = 1; print (a and 1) + 2;", Opcode::Add));
+        assert!(contains("var a = 1; print (a or 1) + 2;", Opcode::Add));
+        // Type errors are left to the VM to report
+        assert!(contains("print 1 + nil;", Opcode::Add));
+        assert!(contains("print \"a\" + 1;", Opcode::Add));
+        assert!(contains("print -\"a\";", Opcode::Neg));
+        assert!(contains("print true < false;", Opcode::Less));
+    }
+
+    #[test]
+    fn lowered_code() {
+        let op = |opcode: Opcode| -> u8 { opcode.into() };
+        let script = compile_script("var a = 1; print a.b(2) or -a;", &mut Heap::default());
+        assert_eq!(script.chunk.code, vec![
+            op(Opcode::Constant), 1, op(Opcode::DefineGlobal), 0,
+            op(Opcode::GetGlobal), 0, op(Opcode::Constant), 3, op(Opcode::Invoke), 2, 1,
+            op(Opcode::JumpIfFalse), 0, 3, op(Opcode::Jump), 0, 4,
+            op(Opcode::Pop), op(Opcode::GetGlobal), 0, op(Opcode::Neg),
+            op(Opcode::Print), op(Opcode::Nil), op(Opcode::Return),
+        ]);
+        // Code is blamed on the token the parser had just consumed
+        assert_eq!(script.chunk.span_at(1).column, 9); // `1`
+        assert_eq!(script.chunk.span_at(3).column, 10); // `;`
+        assert_eq!(script.chunk.span_at(11).column, 25); // `or`
+        assert_eq!(script.chunk.span_at(20).column, 28); // `-`
+    }
+
+    #[test]
+    fn long_chains_compile() {
+        let source = format!("var a = 0; print a{};", " + a".repeat(10_000));
+        let script = compile_script(&source, &mut Heap::default());
+        assert_eq!(script.chunk.code.iter().filter(|&&byte| byte == Opcode::Add.into()).count(), 10_000);
+    }
+
+    #[test]
+    fn every_function_is_optimized() {
+        let mut heap = Heap::default();
+        let script = compile_with("fun f(a) { return !(a == 1); } !(f == f);", &mut heap, true);
+        assert!(!script.chunk.code.contains(&Opcode::Not.into()));
+        let f = script.chunk.constants.iter()
+            .find_map(|constant| match heap.get(constant.as_obj()?) {
+                Obj::Function(function) => Some(function),
+                _ => None,
+            })
+            .unwrap();
+        assert!(f.chunk.code.contains(&Opcode::NotEqual.into()));
+        assert!(!f.chunk.code.contains(&Opcode::Not.into()));
     }
 }
@@ -0,0 +1,348 @@
+use std::mem;
+use std::rc::Rc;
+
+use crate::interner::Interner;
+use crate::value::{Class, Closure, Function, Instance, Obj, Upvalue, Value};
+
+// How many bytes to allocate before the first collection
+const FIRST_GC: usize = 1024 * 1024;
+// How much the heap may grow after a collection before the next one
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+// A handle to an object in a `Heap`. It is only meaningful
+// for the heap that allocated it, and only while the object is reachable
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(u32);
+
+impl ObjRef {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    // The handle's bits, for packing it into a NaN-boxed value
+    #[cfg_attr(not(feature = "nan-boxing"), allow(dead_code))]
+    pub(crate) fn raw(self) -> u32 {
+        self.0
+    }
+
+    #[cfg_attr(not(feature = "nan-boxing"), allow(dead_code))]
+    pub(crate) fn from_raw(raw: u32) -> ObjRef {
+        ObjRef(raw)
+    }
+}
+
+// Something that holds references to objects in the heap
+pub trait Trace {
+    fn trace(&self, marker: &mut Marker);
+}
+
+// The state of the mark phase of a collection
+pub struct Marker {
+    marked: Vec<bool>,
+    // Objects that are marked but whose references haven't been traced yet
+    gray: Vec<ObjRef>,
+}
+
+impl Marker {
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        if self.marked[obj.index()] {
+            return;
+        }
+        self.marked[obj.index()] = true;
+        self.gray.push(obj);
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Some(obj) = value.as_obj() {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn is_marked(&self, obj: ObjRef) -> bool {
+        self.marked[obj.index()]
+    }
+}
+
+impl Trace for Value {
+    fn trace(&self, marker: &mut Marker) {
+        marker.mark_value(*self);
+    }
+}
+
+impl Trace for ObjRef {
+    fn trace(&self, marker: &mut Marker) {
+        marker.mark_object(*self);
+    }
+}
+
+impl<T: Trace> Trace for [T] {
+    fn trace(&self, marker: &mut Marker) {
+        for item in self {
+            item.trace(marker);
+        }
+    }
+}
+
+impl<T: Trace, const N: usize> Trace for [T; N] {
+    fn trace(&self, marker: &mut Marker) {
+        self[..].trace(marker);
+    }
+}
+
+impl Trace for Function {
+    fn trace(&self, marker: &mut Marker) {
+        self.chunk.constants.trace(marker);
+    }
+}
+
+impl Trace for Obj {
+    fn trace(&self, marker: &mut Marker) {
+        match self {
+            Obj::String(_) | Obj::Native(_) => (),
+            Obj::Function(function) => function.trace(marker),
+            Obj::Closure(closure) => {
+                closure.function.trace(marker);
+                closure.upvalues.trace(marker);
+            },
+            Obj::Upvalue(Upvalue::Open(_)) => (),
+            Obj::Upvalue(Upvalue::Closed(value)) => marker.mark_value(*value),
+            Obj::Class(class) => class.methods.trace(marker),
+            Obj::Instance(instance) => {
+                marker.mark_object(instance.class);
+                instance.fields.trace(marker);
+            },
+            Obj::BoundMethod(bound) => {
+                marker.mark_value(bound.receiver);
+                marker.mark_object(bound.method);
+            },
+        }
+    }
+}
+
+impl Obj {
+    // An estimate of the memory the object owns, used to schedule collections
+    fn size(&self) -> usize {
+        mem::size_of::<Obj>() + match self {
+            Obj::String(s) => s.len(),
+            Obj::Function(function) => {
+                function.chunk.code.len() + function.chunk.constants.len() * mem::size_of::<Value>()
+            },
+            Obj::Closure(closure) => closure.upvalues.len() * mem::size_of::<ObjRef>(),
+            _ => 0,
+        }
+    }
+}
+
+// Every object a VM allocates, reclaimed by a tracing mark-sweep collector.
+// Allocating never collects by itself: the owner of the heap calls
+// `collect` with its roots once `should_collect` says it's time, at a
+// point where every live object is reachable from those roots
+pub struct Heap {
+    objects: Vec<Option<Obj>>, // None marks a free slot
+    sizes: Vec<usize>,         // What each object counted towards `bytes_allocated`
+    free: Vec<ObjRef>,         // Free slots to reuse
+    strings: Interner,
+    bytes_allocated: usize,
+    next_gc: usize,
+    stress: bool, // Collect at every opportunity, to shake out rooting bugs
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            objects: vec![],
+            sizes: vec![],
+            free: vec![],
+            strings: Interner::default(),
+            bytes_allocated: 0,
+            next_gc: FIRST_GC,
+            stress: false,
+        }
+    }
+}
+
+impl Heap {
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = obj.size();
+        self.bytes_allocated += size;
+
+        match self.free.pop() {
+            Some(slot) => {
+                self.objects[slot.index()] = Some(obj);
+                self.sizes[slot.index()] = size;
+                slot
+            },
+            None => {
+                self.objects.push(Some(obj));
+                self.sizes.push(size);
+                ObjRef((self.objects.len() - 1) as u32)
+            },
+        }
+    }
+
+    // Return the one string object with the given contents, allocating it if needed
+    pub fn intern(&mut self, s: &str) -> ObjRef {
+        if let Some(interned) = self.strings.get(s) {
+            return interned;
+        }
+
+        let interned = self.alloc(Obj::String(s.into()));
+        self.strings.insert(s, interned);
+        interned
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    // Free every object that can't be reached from `roots`
+    pub fn collect(&mut self, roots: &dyn Trace) {
+        let mut marker = Marker {
+            marked: vec![false; self.objects.len()],
+            gray: vec![],
+        };
+
+        roots.trace(&mut marker);
+        while let Some(obj) = marker.gray.pop() {
+            if let Some(obj) = &self.objects[obj.index()] {
+                obj.trace(&mut marker);
+            }
+        }
+
+        // The intern table holds its strings weakly
+        self.strings.remove_unmarked(&marker);
+
+        for (i, slot) in self.objects.iter_mut().enumerate() {
+            if slot.is_some() && !marker.marked[i] {
+                *slot = None;
+                self.bytes_allocated -= self.sizes[i];
+                self.free.push(ObjRef(i as u32));
+            }
+        }
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(FIRST_GC);
+    }
+
+    pub fn live_objects(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        self.objects[obj.index()].as_ref().expect("Use of a freed object")
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        self.objects[obj.index()].as_mut().expect("Use of a freed object")
+    }
+
+    // Accessors for objects whose type is already known
+
+    pub fn string(&self, obj: ObjRef) -> &str {
+        match self.get(obj) {
+            Obj::String(s) => s,
+            _ => unreachable!("Expected a string"),
+        }
+    }
+
+    pub fn function(&self, obj: ObjRef) -> &Rc<Function> {
+        match self.get(obj) {
+            Obj::Function(function) => function,
+            _ => unreachable!("Expected a function"),
+        }
+    }
+
+    pub fn closure(&self, obj: ObjRef) -> &Closure {
+        match self.get(obj) {
+            Obj::Closure(closure) => closure,
+            _ => unreachable!("Expected a closure"),
+        }
+    }
+
+    pub fn upvalue(&self, obj: ObjRef) -> &Upvalue {
+        match self.get(obj) {
+            Obj::Upvalue(upvalue) => upvalue,
+            _ => unreachable!("Expected an upvalue"),
+        }
+    }
+
+    pub fn upvalue_mut(&mut self, obj: ObjRef) -> &mut Upvalue {
+        match self.get_mut(obj) {
+            Obj::Upvalue(upvalue) => upvalue,
+            _ => unreachable!("Expected an upvalue"),
+        }
+    }
+
+    pub fn class(&self, obj: ObjRef) -> &Class {
+        match self.get(obj) {
+            Obj::Class(class) => class,
+            _ => unreachable!("Expected a class"),
+        }
+    }
+
+    pub fn class_mut(&mut self, obj: ObjRef) -> &mut Class {
+        match self.get_mut(obj) {
+            Obj::Class(class) => class,
+            _ => unreachable!("Expected a class"),
+        }
+    }
+
+    pub fn instance(&self, obj: ObjRef) -> &Instance {
+        match self.get(obj) {
+            Obj::Instance(instance) => instance,
+            _ => unreachable!("Expected an instance"),
+        }
+    }
+
+    pub fn instance_mut(&mut self, obj: ObjRef) -> &mut Instance {
+        match self.get_mut(obj) {
+            Obj::Instance(instance) => instance,
+            _ => unreachable!("Expected an instance"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::heap::{Heap, ObjRef};
+    use crate::table::Table;
+    use crate::value::{Class, Instance, Obj, Value};
+
+    #[test]
+    fn unreachable_objects_are_freed() {
+        let mut heap = Heap::default();
+        let kept = heap.intern("kept");
+        heap.intern("garbage");
+        assert_eq!(heap.live_objects(), 2);
+
+        heap.collect(&[Value::obj(kept)]);
+        assert_eq!(heap.live_objects(), 1);
+        assert_eq!(heap.string(kept), "kept");
+        // The intern table forgets freed strings, so this is a new object
+        let garbage = heap.intern("garbage");
+        assert_eq!(heap.string(garbage), "garbage");
+        assert_eq!(heap.live_objects(), 2);
+    }
+
+    #[test]
+    fn cycles_are_freed() {
+        let mut heap = Heap::default();
+        let class = heap.alloc(Obj::Class(Class { name: "A".to_owned(), methods: Table::default() }));
+        let a = heap.alloc(Obj::Instance(Instance { class, fields: Table::default() }));
+        let b = heap.alloc(Obj::Instance(Instance { class, fields: Table::default() }));
+        let name = heap.intern("other");
+        heap.instance_mut(a).fields.insert(name, Value::obj(b));
+        heap.instance_mut(b).fields.insert(name, Value::obj(a));
+
+        // Reachable through the cycle
+        heap.collect(&[a]);
+        assert_eq!(heap.live_objects(), 4);
+
+        let roots: [ObjRef; 0] = [];
+        heap.collect(&roots);
+        assert_eq!(heap.live_objects(), 0);
+    }
+}
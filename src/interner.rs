@@ -0,0 +1,43 @@
+use crate::heap::{Marker, ObjRef};
+use crate::table::Table;
+use crate::value::Value;
+
+// The set of every distinct string in a heap. Interned strings can
+// be compared by identity, since equal strings are the same object
+#[derive(Default)]
+pub struct Interner {
+    // Maps each string's contents to the value holding its one object
+    strings: Table<String>,
+}
+
+impl Interner {
+    pub fn get(&self, s: &str) -> Option<ObjRef> {
+        self.strings.get(s).and_then(|value| value.as_obj())
+    }
+
+    pub fn insert(&mut self, s: &str, interned: ObjRef) {
+        self.strings.insert(s.to_owned(), Value::obj(interned));
+    }
+
+    // Forget the strings a collection is about to free
+    pub fn remove_unmarked(&mut self, marker: &Marker) {
+        self.strings.retain(|_, value| value.as_obj().is_some_and(|interned| marker.is_marked(interned)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::heap::Heap;
+
+    #[test]
+    fn equal_strings_share_one_object() {
+        let mut heap = Heap::default();
+        let a = heap.intern("hello");
+        let b = heap.intern(&("hel".to_owned() + "lo"));
+        let c = heap.intern("world");
+        assert!(a == b);
+        assert!(a != c);
+        assert_eq!(heap.string(c), "world");
+        assert_eq!(heap.live_objects(), 2);
+    }
+}
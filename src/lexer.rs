@@ -5,9 +5,12 @@ use crate::token::{Token, TokenType};
 pub struct Lexer<'a> {
     source: &'a str,    // The source string to be lexed
     iter: MultiPeek<Chars<'a>>,
-    start: usize,       // The index of the start of the current lexeme
-    current: usize,     // The index of the current character
+    start: usize,       // The byte offset of the start of the current lexeme
+    current: usize,     // The byte offset of the current character
     line: usize,        // The current source line number
+    column: usize,      // The column of the current character
+    start_line: usize,  // The line of the start of the current lexeme
+    start_column: usize, // The column of the start of the current lexeme
 }
 
 impl<'a> Lexer<'a> {
@@ -18,6 +21,9 @@ impl<'a> Lexer<'a> {
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
@@ -32,22 +38,31 @@ impl<'a> Lexer<'a> {
             self.iter.reset_peek();
             false
         } else {
-            self.current += 1;
-            self.iter.next().unwrap();
+            self.advance();
             true
         }
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.iter.next().unwrap()
+        let c = self.iter.next().unwrap();
+        self.current += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        c
     }
 
     fn make_token(&self, token_type: TokenType) -> Token<'a> {
         Token {
             token_type,
             lexeme: &self.source[self.start..self.current],
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            offset: self.start,
+            length: self.current - self.start,
         }
     }
 
@@ -55,7 +70,10 @@ impl<'a> Lexer<'a> {
         Token {
             token_type: TokenType::Error,
             lexeme: msg,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            offset: self.start,
+            length: self.current - self.start,
         }
     }
 
@@ -63,11 +81,7 @@ impl<'a> Lexer<'a> {
         while !self.is_at_end() {
             match self.iter.peek() {
                 Some('"') => break,
-                // Support multi-line strings
-                Some('\n') => {
-                    self.line += 1;
-                    self.advance()
-                },
+                // Multi-line strings are fine; advancing counts the lines
                 _ => self.advance()
             };
         }
@@ -92,12 +106,9 @@ impl<'a> Lexer<'a> {
         self.consume_digits();
 
         // Look for a fractional part
-        match self.iter.peek() {
-            Some('.') => {
-                // Second char of lookahead to see if we should consume '.'
-                self.consume_digits();
-            },
-            _ => (),
+        if let Some('.') = self.iter.peek() {
+            // Second char of lookahead to see if we should consume '.'
+            self.consume_digits();
         }
         self.iter.reset_peek();
 
@@ -108,12 +119,13 @@ impl<'a> Lexer<'a> {
         &mut self,
         start: usize, length: usize, rest: &str, token_type: TokenType
     ) -> Token<'a> {
+        // A keyword must match the whole lexeme, not just a prefix of it
         if self.current - self.start != start + length {
-            // TODO: return Identifier? (short circuit)
+            return self.make_token(TokenType::Identifier);
         }
         let keyword_start = self.start + start;
         let keyword_end = self.start + start + length;
-        if &self.source[keyword_start..keyword_end] == rest {
+        if self.source.get(keyword_start..keyword_end) == Some(rest) {
             self.make_token(token_type)
         } else {
             self.make_token(TokenType::Identifier)
@@ -132,12 +144,12 @@ impl<'a> Lexer<'a> {
 
         // Check if identifier matches any reserved keywords
         // Basically a tiny trie to avoid having to match on the entire token
-        match &self.source[self.start..(self.start+1)] {
+        match self.source.get(self.start..(self.start+1)).unwrap_or("") {
             "a" => self.check_keyword(1, 2, "nd", TokenType::And),
             "c" => self.check_keyword(1, 4, "lass", TokenType::Class),
             "e" => self.check_keyword(1, 3, "lse", TokenType::Else),
             "f" if self.current - self.start > 1 => {
-                match &self.source[(self.start+1)..(self.start+2)] {
+                match self.source.get((self.start+1)..(self.start+2)).unwrap_or("") {
                     "a" => self.check_keyword(2, 3, "lse", TokenType::False),
                     "o" => self.check_keyword(2, 1, "r", TokenType::For),
                     "u" => self.check_keyword(2, 1, "n", TokenType::Fun),
@@ -151,7 +163,7 @@ impl<'a> Lexer<'a> {
             "r" => self.check_keyword(1, 5, "eturn", TokenType::Return),
             "s" => self.check_keyword(1, 4, "uper", TokenType::Super),
             "t" if self.current - self.start > 1 => {
-                match &self.source[(self.start+1)..(self.start+2)] {
+                match self.source.get((self.start+1)..(self.start+2)).unwrap_or("") {
                     "h" => self.check_keyword(2, 2, "is", TokenType::This),
                     "r" => self.check_keyword(2, 2, "ue", TokenType::True),
                     _ => self.make_token(TokenType::Identifier),
@@ -177,11 +189,7 @@ impl<'a> Lexer<'a> {
     fn skip_whitespace(&mut self) {
         loop {
             match self.iter.peek() {
-                Some(' ') | Some('\r') | Some('\t') => { self.advance(); },
-                Some('\n') => {
-                    self.line += 1;
-                    self.advance();
-                },
+                Some(' ') | Some('\r') | Some('\t') | Some('\n') => { self.advance(); },
                 Some('/') => {
                     // Second char of lookahead
                     match self.iter.peek() {
@@ -198,6 +206,8 @@ impl<'a> Lexer<'a> {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
 
         if self.is_at_end() {
             return self.make_token(TokenType::EOF);
@@ -1,8 +1,14 @@
-#![feature(box_patterns)]
-
+pub mod ast;
 pub mod chunk;
 pub mod compiler;
+pub mod heap;
+pub mod interner;
 pub mod lexer;
+pub mod natives;
+pub mod optimizer;
+pub mod parser;
+pub mod register;
+pub mod report;
 pub mod table;
 pub mod token;
 pub mod value;
@@ -1,32 +1,31 @@
 use std::env;
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::process;
 
-use lox::vm::{VM, InterpretError};
+use lox::report;
+use lox::vm::{Backend, VM};
 
-fn repl() {
+fn repl(mut vm: VM) {
     let stdin = io::stdin();
-    let mut vm = VM::new();
 
     loop {
         print!("> ");
         io::stdout().flush().unwrap();
 
         let mut input = String::new();
-        if !stdin.read_line(&mut input).is_ok() {
+        if stdin.read_line(&mut input).is_err() {
             eprintln!("Could not read from stdin");
             return;
         }
 
-        match vm.interpret(&input) {
-            Ok(()) => (),
-            Err(InterpretError::CompileError) => println!("Compile error!"),
-            Err(InterpretError::RuntimeError) => println!("Runtime error!"),
+        if let Err(error) = vm.interpret_named(&input, "<repl>") {
+            eprint!("{}", report::render(&error));
         }
     }
 }
 
-fn run_file(filename: &str) {
+fn run_file(mut vm: VM, filename: &str) {
     let mut file = match File::open(filename) {
         Ok(file) => file,
         Err(_) => { eprintln!("Could not find file {}", filename); return }
@@ -37,20 +36,35 @@ fn run_file(filename: &str) {
         Err(_) => { eprintln!("Failed to read from file"); return },
     }
 
-    match VM::new().interpret(&source) {
-        Ok(()) => (),
-        Err(InterpretError::CompileError) => println!("Compile error!"),
-        Err(InterpretError::RuntimeError) => println!("Runtime error!"),
+    if let Err(error) = vm.interpret_named(&source, filename) {
+        eprint!("{}", report::render(&error));
     }
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
-        repl();
-    } else if args.len() == 2 {
-        run_file(&args[1]);
-    } else {
-        println!("Usage: lox [path]");
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut vm = VM::new();
+    let no_optimize = args.iter().any(|arg| arg == "--no-optimize");
+    let register = args.iter().any(|arg| arg == "--register");
+    if no_optimize {
+        // Only stack code goes through the peephole optimizer
+        if register {
+            eprintln!("warning: --no-optimize has no effect with --register");
+        }
+        vm.set_optimize(false);
+        args.retain(|arg| arg != "--no-optimize");
+    }
+    if register {
+        vm.set_backend(Backend::Register);
+        args.retain(|arg| arg != "--register");
+    }
+
+    match args.as_slice() {
+        [] => repl(vm),
+        [path] => run_file(vm, path),
+        _ => {
+            eprintln!("Usage: lox [--no-optimize] [--register] [path]");
+            process::exit(64);
+        },
     }
 }
@@ -0,0 +1,40 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::heap::Heap;
+use crate::value::{NativeFn, Obj, Value};
+
+// The natives every VM starts with, as (name, arity, function)
+pub const STANDARD: [(&str, usize, NativeFn); 4] = [
+    ("clock", 0, clock),
+    ("sqrt", 1, sqrt),
+    ("str", 1, str),
+    ("len", 1, len),
+];
+
+// Seconds since the Unix epoch
+fn clock(_: &mut Heap, _: &[Value]) -> Result<Value, String> {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map_err(|_| "System clock is before the Unix epoch".to_owned())?;
+    Ok(Value::number(elapsed.as_secs_f64()))
+}
+
+fn sqrt(_: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    match args[0].as_number() {
+        Some(n) => Ok(Value::number(n.sqrt())),
+        None => Err("sqrt() expects a number".to_owned()),
+    }
+}
+
+// Convert any value to the string `print` would show for it
+fn str(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let s = args[0].display(heap).to_string();
+    Ok(Value::obj(heap.intern(&s)))
+}
+
+// The length of a string in characters
+fn len(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    match args[0].as_obj().map(|obj| heap.get(obj)) {
+        Some(Obj::String(s)) => Ok(Value::number(s.chars().count() as f64)),
+        _ => Err("len() expects a string".to_owned()),
+    }
+}
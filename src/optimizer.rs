@@ -0,0 +1,283 @@
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, Opcode};
+use crate::heap::Heap;
+use crate::token::Span;
+
+// A peephole optimizer. It slides a small window over a chunk's instructions,
+// replaces runs that match a rule with cheaper code that does the same
+// thing, and then moves every jump to where its target ended up
+
+// An instruction of the chunk being optimized
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub offset: usize, // Where it was in the original chunk
+    pub code: Vec<u8>, // The opcode followed by its operands
+    pub span: Span,
+}
+
+impl Instruction {
+    fn opcode(&self) -> Opcode {
+        Opcode::from(self.code[0])
+    }
+
+    // An operand-less instruction standing in for this one
+    fn replace_with(&self, opcode: Opcode) -> Instruction {
+        Instruction { offset: self.offset, code: vec![opcode.into()], span: self.span }
+    }
+}
+
+// A rewrite of runs of `window` consecutive instructions
+pub struct Rule {
+    pub name: &'static str,
+    pub window: usize,
+    // What to replace the run with, or None if the rule doesn't apply to it
+    pub rewrite: fn(&[Instruction]) -> Option<Vec<Instruction>>,
+}
+
+pub const RULES: [Rule; 3] = [NEGATED_EQUALITY, UNUSED_VALUE, DOUBLE_NEGATION];
+
+// `!(a == b)` is `a != b`, and `!(a != b)` is `a == b`
+pub const NEGATED_EQUALITY: Rule = Rule { name: "negated equality", window: 2, rewrite: negated_equality };
+
+fn negated_equality(window: &[Instruction]) -> Option<Vec<Instruction>> {
+    let negated = match (window[0].opcode(), window[1].opcode()) {
+        (Opcode::Equal, Opcode::Not) => Opcode::NotEqual,
+        (Opcode::NotEqual, Opcode::Not) => Opcode::Equal,
+        _ => return None,
+    };
+    Some(vec![window[0].replace_with(negated)])
+}
+
+// A value that's popped as soon as it's pushed, like that
+// of the expression statement `1;`, needn't be pushed at all
+pub const UNUSED_VALUE: Rule = Rule { name: "unused value", window: 2, rewrite: unused_value };
+
+fn unused_value(window: &[Instruction]) -> Option<Vec<Instruction>> {
+    match (window[0].opcode(), window[1].opcode()) {
+        (Opcode::Constant | Opcode::ConstantLong | Opcode::Nil | Opcode::True | Opcode::False
+         | Opcode::GetLocal | Opcode::GetUpvalue, Opcode::Pop) => Some(vec![]),
+        _ => None,
+    }
+}
+
+// Negating a value twice gives it back, if it's already of the type the
+// negation produces: a bool for `!`, or a number for `-`. Otherwise the
+// first negation converts it, or fails, and must stay
+pub const DOUBLE_NEGATION: Rule = Rule { name: "double negation", window: 3, rewrite: double_negation };
+
+fn double_negation(window: &[Instruction]) -> Option<Vec<Instruction>> {
+    let makes_bool = matches!(window[0].opcode(),
+        Opcode::Not | Opcode::True | Opcode::False | Opcode::Equal | Opcode::NotEqual
+        | Opcode::Greater | Opcode::GreaterEqual | Opcode::Less | Opcode::LessEqual);
+    let makes_number = matches!(window[0].opcode(), Opcode::Neg | Opcode::Sub | Opcode::Mul | Opcode::Div);
+    match (window[1].opcode(), window[2].opcode()) {
+        (Opcode::Not, Opcode::Not) if makes_bool => Some(vec![window[0].clone()]),
+        (Opcode::Neg, Opcode::Neg) if makes_number => Some(vec![window[0].clone()]),
+        _ => None,
+    }
+}
+
+// Optimize `chunk` with every rule
+pub fn optimize(chunk: &mut Chunk, heap: &Heap) {
+    apply(chunk, heap, &RULES);
+}
+
+// Rewrite `chunk` with `rules` until none of them applies anywhere.
+// `heap` holds the functions the chunk's closures are made from
+pub fn apply(chunk: &mut Chunk, heap: &Heap, rules: &[Rule]) {
+    let mut instructions = decode(chunk, heap);
+    let targets: BTreeSet<usize> = instructions.iter().filter_map(jump_target).collect();
+
+    let mut i = 0;
+    while i < instructions.len() {
+        let rewrite = rules.iter().find_map(|rule| {
+            let window = instructions.get(i..i + rule.window)?;
+            // Code in the window must only be reachable from its start,
+            // otherwise a jump into it could skip half of a rewrite. A jump
+            // to code an earlier rewrite removed lands on what came after it
+            let lands_on = |pair: &[Instruction]| {
+                targets.range(pair[0].offset + 1..=pair[1].offset).next().is_some()
+            };
+            if window.windows(2).any(lands_on) {
+                return None;
+            }
+            Some((rule.window, (rule.rewrite)(window)?))
+        });
+        match rewrite {
+            Some((window, replacement)) => {
+                instructions.splice(i..i + window, replacement);
+                // The replacement may complete a match with the code before it
+                i = i.saturating_sub(2);
+            },
+            None => i += 1,
+        }
+    }
+
+    assemble(chunk, &instructions);
+}
+
+fn decode(chunk: &Chunk, heap: &Heap) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let len = chunk.instruction_len(offset, heap);
+        let code = chunk.code[offset..offset + len].to_vec();
+        instructions.push(Instruction { offset, code, span: chunk.span_at(offset) });
+        offset += len;
+    }
+    instructions
+}
+
+// Where a jump instruction lands in the original chunk
+fn jump_target(instruction: &Instruction) -> Option<usize> {
+    let distance = || (instruction.code[1] as usize) << 8 | instruction.code[2] as usize;
+    let next = instruction.offset + 3;
+    match instruction.opcode() {
+        Opcode::Jump | Opcode::JumpIfFalse => Some(next + distance()),
+        Opcode::Loop => Some(next - distance()),
+        _ => None,
+    }
+}
+
+// Replace the code in `chunk` with `instructions`, pointing each jump
+// at its target's new offset. Rewrites only ever shrink the code,
+// so the new distances always fit
+fn assemble(chunk: &mut Chunk, instructions: &[Instruction]) {
+    let mut offsets = Vec::with_capacity(instructions.len());
+    let mut end = 0;
+    for instruction in instructions {
+        offsets.push(end);
+        end += instruction.code.len();
+    }
+    // A jump to an instruction that was removed lands on the one after it
+    let relocate = |target: usize| {
+        let i = instructions.partition_point(|instruction| instruction.offset < target);
+        offsets.get(i).copied().unwrap_or(end)
+    };
+
+    let mut optimized = Chunk::with_source(Rc::clone(&chunk.source));
+    optimized.take_constants(chunk);
+    for (instruction, &offset) in instructions.iter().zip(&offsets) {
+        let mut code = instruction.code.clone();
+        if let Some(target) = jump_target(instruction) {
+            let (next, target) = (offset + 3, relocate(target));
+            let distance = if instruction.opcode() == Opcode::Loop { next - target } else { target - next };
+            code[1] = (distance >> 8) as u8;
+            code[2] = distance as u8;
+        }
+        for byte in code {
+            optimized.write(byte, instruction.span);
+        }
+    }
+    *chunk = optimized;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{Chunk, Opcode};
+    use crate::heap::Heap;
+    use crate::optimizer::{self, Rule, DOUBLE_NEGATION, NEGATED_EQUALITY, UNUSED_VALUE};
+    use crate::token::Span;
+    use crate::value::Value;
+
+    fn span(line: usize) -> Span {
+        Span { line, column: 1, offset: 0, length: 0 }
+    }
+
+    // Build a chunk with one line per instruction
+    fn chunk(instructions: &[&[u8]]) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::number(1.0));
+        for (i, instruction) in instructions.iter().enumerate() {
+            for &byte in *instruction {
+                chunk.write(byte, span(i + 1));
+            }
+        }
+        chunk
+    }
+
+    fn op(opcode: Opcode) -> u8 {
+        opcode.into()
+    }
+
+    fn apply(rule: Rule, instructions: &[&[u8]]) -> Vec<u8> {
+        let mut chunk = chunk(instructions);
+        optimizer::apply(&mut chunk, &Heap::default(), &[rule]);
+        chunk.code
+    }
+
+    #[test]
+    fn negated_equality() {
+        let equal = op(Opcode::Equal);
+        let not_equal = op(Opcode::NotEqual);
+        let not = op(Opcode::Not);
+        assert_eq!(apply(NEGATED_EQUALITY, &[&[equal], &[not]]), vec![not_equal]);
+        assert_eq!(apply(NEGATED_EQUALITY, &[&[not_equal], &[not]]), vec![equal]);
+        assert_eq!(apply(NEGATED_EQUALITY, &[&[op(Opcode::Less)], &[not]]), vec![op(Opcode::Less), not]);
+    }
+
+    #[test]
+    fn unused_value() {
+        let pop = op(Opcode::Pop);
+        let ret = op(Opcode::Return);
+        assert_eq!(apply(UNUSED_VALUE, &[&[op(Opcode::Constant), 0], &[pop], &[ret]]), vec![ret]);
+        assert_eq!(apply(UNUSED_VALUE, &[&[op(Opcode::GetLocal), 1], &[pop], &[ret]]), vec![ret]);
+        // Getting a global can fail, so it has to happen
+        let get_global: &[u8] = &[op(Opcode::GetGlobal), 0];
+        assert_eq!(apply(UNUSED_VALUE, &[get_global, &[pop]]), vec![op(Opcode::GetGlobal), 0, pop]);
+    }
+
+    #[test]
+    fn double_negation() {
+        let (not, neg) = (op(Opcode::Not), op(Opcode::Neg));
+        let less = op(Opcode::Less);
+        assert_eq!(apply(DOUBLE_NEGATION, &[&[less], &[not], &[not]]), vec![less]);
+        assert_eq!(apply(DOUBLE_NEGATION, &[&[not], &[not], &[not]]), vec![not]);
+        assert_eq!(apply(DOUBLE_NEGATION, &[&[op(Opcode::Mul)], &[neg], &[neg]]), vec![op(Opcode::Mul)]);
+        // `!!nil` is false and `--"a"` is an error, so these stay
+        let nil = op(Opcode::Nil);
+        assert_eq!(apply(DOUBLE_NEGATION, &[&[nil], &[not], &[not]]), vec![nil, not, not]);
+        let add = op(Opcode::Add);
+        assert_eq!(apply(DOUBLE_NEGATION, &[&[add], &[neg], &[neg]]), vec![add, neg, neg]);
+    }
+
+    #[test]
+    fn spans_follow_their_instructions() {
+        let mut chunk = chunk(&[&[op(Opcode::Constant), 0], &[op(Opcode::Pop)],
+                                &[op(Opcode::Equal)], &[op(Opcode::Not)], &[op(Opcode::Return)]]);
+        optimizer::optimize(&mut chunk, &Heap::default());
+        assert_eq!(chunk.code, vec![op(Opcode::NotEqual), op(Opcode::Return)]);
+        assert_eq!((chunk.line_at(0), chunk.line_at(1)), (3, 5));
+    }
+
+    #[test]
+    fn jumps_are_moved() {
+        let (constant, pop) = (op(Opcode::Constant), op(Opcode::Pop));
+        let code = apply(UNUSED_VALUE, &[
+            &[constant, 0], &[pop],             // 0: Removed, but a loop lands here
+            &[op(Opcode::JumpIfFalse), 0, 3],   // 3
+            &[constant, 0], &[pop],             // 6: Removed
+            &[op(Opcode::Nil)],                 // 9: The jump lands here
+            &[op(Opcode::Loop), 0, 13],         // 10
+            &[op(Opcode::Return)],
+        ]);
+        assert_eq!(code, vec![op(Opcode::JumpIfFalse), 0, 0, op(Opcode::Nil),
+                              op(Opcode::Loop), 0, 7, op(Opcode::Return)]);
+    }
+
+    #[test]
+    fn jumps_into_a_match_block_it() {
+        // A jump landing on the `Not` means it may negate some other value
+        let (equal, not, ret) = (op(Opcode::Equal), op(Opcode::Not), op(Opcode::Return));
+        let jump: &[u8] = &[op(Opcode::Jump), 0, 1];
+        let code = apply(NEGATED_EQUALITY, &[jump, &[equal], &[not], &[ret]]);
+        assert_eq!(code, vec![op(Opcode::Jump), 0, 1, equal, not, ret]);
+
+        // It still does once the code it landed on is removed
+        let mut chunk = chunk(&[jump, &[equal], &[op(Opcode::Constant), 0], &[op(Opcode::Pop)], &[not], &[ret]]);
+        optimizer::optimize(&mut chunk, &Heap::default());
+        assert_eq!(chunk.code, vec![op(Opcode::Jump), 0, 1, equal, not, ret]);
+    }
+}
@@ -0,0 +1,766 @@
+use std::rc::Rc;
+
+use crate::ast::{BinaryOp, Class, Expr, Function, Identifier, LogicalOp, Program, Stmt, UnaryOp};
+use crate::chunk::Source;
+use crate::compiler::{Diagnostic, ErrorToken};
+use crate::lexer::Lexer;
+use crate::token::{Span, Token, TokenType};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl From<Precedence> for u8 {
+    fn from(prec: Precedence) -> u8 {
+        match prec {
+            Precedence::None       => 0,
+            Precedence::Assignment => 1,
+            Precedence::Or         => 2,
+            Precedence::And        => 3,
+            Precedence::Equality   => 4,
+            Precedence::Comparison => 5,
+            Precedence::Term       => 6,
+            Precedence::Factor     => 7,
+            Precedence::Unary      => 8,
+            Precedence::Call       => 9,
+            Precedence::Primary    => 10,
+        }
+    }
+}
+
+impl From<u8> for Precedence {
+    fn from(n: u8) -> Precedence {
+        match n {
+            1 =>  Precedence::Assignment,
+            2 =>  Precedence::Or,
+            3 =>  Precedence::And,
+            4 =>  Precedence::Equality,
+            5 =>  Precedence::Comparison,
+            6 =>  Precedence::Term,
+            7 =>  Precedence::Factor,
+            8 =>  Precedence::Unary,
+            9 =>  Precedence::Call,
+            10 => Precedence::Primary,
+            _  => Precedence::None,
+        }
+    }
+}
+
+impl Precedence {
+    fn plus_one(&self) -> Precedence {
+        (*self as u8 + 1).into()
+    }
+}
+
+// Rules for a given TokenType
+struct ParseRule<'a> {
+    // The function to parse a prefix expression
+    // starting with a token of that type. The flag says
+    // whether the expression may be an assignment target
+    prefix: Option<fn(&mut Parser<'a>, bool) -> Expr<'a>>,
+    // The function to parse an infix expression whose
+    // left operand is followed by a token of that type
+    infix: Option<fn(&mut Parser<'a>, Expr<'a>, bool) -> Expr<'a>>,
+    // The precedence of an infix expression
+    // that uses that token as an operator
+    precedence: Precedence,
+}
+
+fn get_parse_rule<'a>(token_type: TokenType) -> ParseRule<'a> {
+    match token_type {
+        TokenType::LeftParen => ParseRule {
+            prefix: Some(|parser, _| parser.grouping()),
+            infix: Some(|parser, callee, _| parser.call(callee)),
+            precedence: Precedence::Call,
+        },
+        TokenType::Bang => ParseRule {
+            prefix: Some(|parser, _| parser.unary()),
+            infix: None,
+            precedence: Precedence::Term,
+        },
+        TokenType::Dot => ParseRule {
+            prefix: None,
+            infix: Some(|parser, object, can_assign| parser.dot(object, can_assign)),
+            precedence: Precedence::Call,
+        },
+        TokenType::Minus => ParseRule {
+            prefix: Some(|parser, _| parser.unary()),
+            infix: Some(|parser, left, _| parser.binary(left)),
+            precedence: Precedence::Term,
+        },
+        TokenType::Plus => ParseRule {
+            prefix: None,
+            infix: Some(|parser, left, _| parser.binary(left)),
+            precedence: Precedence::Term,
+        },
+        TokenType::Slash => ParseRule {
+            prefix: None,
+            infix: Some(|parser, left, _| parser.binary(left)),
+            precedence: Precedence::Factor,
+        },
+        TokenType::Star => ParseRule {
+            prefix: None,
+            infix: Some(|parser, left, _| parser.binary(left)),
+            precedence: Precedence::Factor,
+        },
+        TokenType::EqualEqual => ParseRule {
+            prefix: None,
+            infix: Some(|parser, left, _| parser.binary(left)),
+            precedence: Precedence::Equality,
+        },
+        TokenType::BangEqual => ParseRule {
+            prefix: None,
+            infix: Some(|parser, left, _| parser.binary(left)),
+            precedence: Precedence::Equality,
+        },
+        TokenType::Greater => ParseRule {
+            prefix: None,
+            infix: Some(|parser, left, _| parser.binary(left)),
+            precedence: Precedence::Comparison,
+        },
+        TokenType::GreaterEqual => ParseRule {
+            prefix: None,
+            infix: Some(|parser, left, _| parser.binary(left)),
+            precedence: Precedence::Comparison,
+        },
+        TokenType::Less => ParseRule {
+            prefix: None,
+            infix: Some(|parser, left, _| parser.binary(left)),
+            precedence: Precedence::Comparison,
+        },
+        TokenType::LessEqual => ParseRule {
+            prefix: None,
+            infix: Some(|parser, left, _| parser.binary(left)),
+            precedence: Precedence::Comparison,
+        },
+        TokenType::Identifier => ParseRule {
+            prefix: Some(|parser, can_assign| parser.variable(can_assign)),
+            infix: None,
+            precedence: Precedence::None,
+        },
+        TokenType::And => ParseRule {
+            prefix: None,
+            infix: Some(|parser, left, _| parser.logical(left, LogicalOp::And, Precedence::And)),
+            precedence: Precedence::And,
+        },
+        TokenType::Or => ParseRule {
+            prefix: None,
+            infix: Some(|parser, left, _| parser.logical(left, LogicalOp::Or, Precedence::Or)),
+            precedence: Precedence::Or,
+        },
+        TokenType::Super => ParseRule {
+            prefix: Some(|parser, _| parser.super_()),
+            infix: None,
+            precedence: Precedence::None,
+        },
+        TokenType::This => ParseRule {
+            prefix: Some(|parser, _| Expr::This { span: parser.previous.span() }),
+            infix: None,
+            precedence: Precedence::None,
+        },
+        TokenType::String => ParseRule {
+            prefix: Some(|parser, _| parser.string()),
+            infix: None,
+            precedence: Precedence::None,
+        },
+        TokenType::Number => ParseRule {
+            prefix: Some(|parser, _| parser.number()),
+            infix: None,
+            precedence: Precedence::None,
+        },
+        TokenType::False => ParseRule {
+            prefix: Some(|parser, _| parser.literal()),
+            infix: None,
+            precedence: Precedence::None,
+        },
+        TokenType::True => ParseRule {
+            prefix: Some(|parser, _| parser.literal()),
+            infix: None,
+            precedence: Precedence::None,
+        },
+        TokenType::Nil => ParseRule {
+            prefix: Some(|parser, _| parser.literal()),
+            infix: None,
+            precedence: Precedence::None,
+        },
+        _ => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    }
+}
+
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+    current: Token<'a>,
+    previous: Token<'a>,
+    source: Rc<Source>, // What's being parsed, for error reports
+    diagnostics: Vec<Diagnostic>, // Every error found so far
+    // Set after an error until the parser reaches a statement boundary,
+    // so one mistake doesn't cause a cascade of confusing errors
+    panic_mode: bool,
+}
+
+// Parse a program's source into its syntax tree. Statements with syntax
+// errors are left out of the tree, and reported in the diagnostics instead
+pub fn parse(source: &Rc<Source>) -> (Program<'_>, Vec<Diagnostic>) {
+    let mut parser = Parser::new(source);
+    let program = parser.program();
+    (program, parser.diagnostics)
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(source: &'a Rc<Source>) -> Parser<'a> {
+        // Neither token is meaningful until the first advance
+        let none = Token {
+            token_type: TokenType::Error,
+            lexeme: "",
+            line: 0,
+            column: 0,
+            offset: 0,
+            length: 0,
+        };
+        Parser {
+            lexer: Lexer::new(&source.text),
+            current: none,
+            previous: none,
+            source: Rc::clone(source),
+            diagnostics: vec![],
+            panic_mode: false,
+        }
+    }
+
+    pub fn program(&mut self) -> Program<'a> {
+        let mut statements = vec![];
+        self.advance();
+        while !self.match_token(TokenType::EOF) {
+            statements.extend(self.declaration());
+        }
+        Program { statements, end: self.previous.span() }
+    }
+
+    // ===================================
+    // Eating tokens
+    // ===================================
+    fn advance(&mut self) {
+        self.previous = self.current;
+
+        // Read and report error tokens, stop when we hit a non-error
+        loop {
+            self.current = self.lexer.lex_token();
+            match self.current.token_type {
+                TokenType::Error => self.error_at_current(self.current.lexeme),
+                _ => break,
+            }
+        }
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current.token_type == token_type
+    }
+
+    // Advance past the current token only if it has the given type
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn consume(&mut self, token_type: TokenType, message: &str) {
+        if self.current.token_type == token_type {
+            self.advance();
+        } else {
+            self.error_at_current(message);
+        }
+    }
+
+    fn error(&mut self, message: &str) {
+        self.error_at(self.previous, message)
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        self.error_at(self.current, message)
+    }
+
+    fn error_at(&mut self, token: Token, message: &str) {
+        if self.panic_mode { return; }
+        self.panic_mode = true;
+
+        let at = match token.token_type {
+            TokenType::EOF => ErrorToken::End,
+            TokenType::Error => ErrorToken::Invalid,
+            _ => ErrorToken::Lexeme(token.lexeme.to_owned()),
+        };
+        let diagnostic = Diagnostic {
+            message: message.to_owned(),
+            source: Rc::clone(&self.source),
+            span: token.span(),
+            token: at,
+        };
+        self.diagnostics.push(diagnostic);
+    }
+
+    // Skip tokens until the end of the current statement or the start of
+    // the next one, where parsing can resume after an error
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.token_type != TokenType::EOF {
+            if self.previous.token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.current.token_type {
+                TokenType::Class | TokenType::Fun | TokenType::Var | TokenType::For |
+                TokenType::If | TokenType::While | TokenType::Print | TokenType::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    // The name in the token just consumed
+    fn identifier(&self) -> Identifier<'a> {
+        Identifier { name: self.previous.lexeme, span: self.previous.span() }
+    }
+
+    // ===================================
+    // Expressions
+    // ===================================
+    fn number(&mut self) -> Expr<'a> {
+        // TODO: handle parse error
+        let value = self.previous.lexeme.parse::<f64>().unwrap();
+        Expr::Number { value, span: self.previous.span() }
+    }
+
+    fn string(&mut self) -> Expr<'a> {
+        // Trim outer quotes
+        let value = &self.previous.lexeme[1..(self.previous.lexeme.len()-1)];
+        Expr::String { value, span: self.previous.span() }
+    }
+
+    fn literal(&mut self) -> Expr<'a> {
+        let span = self.previous.span();
+        match self.previous.token_type {
+            TokenType::False => Expr::Bool { value: false, span },
+            TokenType::True => Expr::Bool { value: true, span },
+            _ => Expr::Nil { span },
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Expr<'a> {
+        let name = self.identifier();
+        if can_assign && self.match_token(TokenType::Equal) {
+            let value = Box::new(self.expression());
+            Expr::Assign { name, value }
+        } else {
+            Expr::Variable(name)
+        }
+    }
+
+    // The arguments of a call whose '(' was just consumed,
+    // and the span of its ')'
+    fn argument_list(&mut self) -> (Vec<Expr<'a>>, Span) {
+        let mut arguments = vec![];
+        if !self.check(TokenType::RightParen) {
+            loop {
+                arguments.push(self.expression());
+                if arguments.len() == u8::MAX as usize + 1 {
+                    self.error("Can't have more than 255 arguments");
+                }
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments");
+        (arguments, self.previous.span())
+    }
+
+    fn call(&mut self, callee: Expr<'a>) -> Expr<'a> {
+        let (arguments, right_paren) = self.argument_list();
+        Expr::Call { callee: Box::new(callee), arguments, right_paren }
+    }
+
+    fn dot(&mut self, object: Expr<'a>, can_assign: bool) -> Expr<'a> {
+        self.consume(TokenType::Identifier, "Expect property name after '.'");
+        let name = self.identifier();
+        let object = Box::new(object);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            let value = Box::new(self.expression());
+            Expr::Set { object, name, value }
+        } else if self.match_token(TokenType::LeftParen) {
+            self.call(Expr::Get { object, name })
+        } else {
+            Expr::Get { object, name }
+        }
+    }
+
+    fn super_(&mut self) -> Expr<'a> {
+        let keyword = self.previous.span();
+        self.consume(TokenType::Dot, "Expect '.' after 'super'");
+        self.consume(TokenType::Identifier, "Expect superclass method name");
+        let method = self.identifier();
+
+        let expr = Expr::Super { keyword, method };
+        if self.match_token(TokenType::LeftParen) {
+            self.call(expr)
+        } else {
+            expr
+        }
+    }
+
+    fn grouping(&mut self) -> Expr<'a> {
+        let left_paren = self.previous.span();
+        let expr = Box::new(self.expression());
+        self.consume(TokenType::RightParen, "Expect ')' after expression");
+        Expr::Grouping { left_paren, expr, right_paren: self.previous.span() }
+    }
+
+    fn unary(&mut self) -> Expr<'a> {
+        let operator = self.previous;
+        let operand = Box::new(self.parse_precedence(Precedence::Unary));
+
+        let op = match operator.token_type {
+            TokenType::Minus => UnaryOp::Negate,
+            _ => UnaryOp::Not,
+        };
+        Expr::Unary { op, op_span: operator.span(), operand }
+    }
+
+    fn binary(&mut self, left: Expr<'a>) -> Expr<'a> {
+        let operator = self.previous;
+        let rule = get_parse_rule(operator.token_type);
+        let right = self.parse_precedence(rule.precedence.plus_one());
+
+        let op = match operator.token_type {
+            TokenType::Plus => BinaryOp::Add,
+            TokenType::Minus => BinaryOp::Sub,
+            TokenType::Star => BinaryOp::Mul,
+            TokenType::Slash => BinaryOp::Div,
+            TokenType::EqualEqual => BinaryOp::Equal,
+            TokenType::BangEqual => BinaryOp::NotEqual,
+            TokenType::Greater => BinaryOp::Greater,
+            TokenType::GreaterEqual => BinaryOp::GreaterEqual,
+            TokenType::Less => BinaryOp::Less,
+            _ => BinaryOp::LessEqual,
+        };
+        Expr::Binary { op, op_span: operator.span(), left: Box::new(left), right: Box::new(right) }
+    }
+
+    fn logical(&mut self, left: Expr<'a>, op: LogicalOp, prec: Precedence) -> Expr<'a> {
+        let op_span = self.previous.span();
+        let right = self.parse_precedence(prec);
+        Expr::Logical { op, op_span, left: Box::new(left), right: Box::new(right) }
+    }
+
+    pub fn expression(&mut self) -> Expr<'a> {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn parse_precedence(&mut self, prec: Precedence) -> Expr<'a> {
+        self.advance();
+        let prefix_rule = get_parse_rule(self.previous.token_type);
+        // Only a low-precedence expression can be the target of an `=`
+        let can_assign = prec <= Precedence::Assignment;
+        let mut expr = match prefix_rule.prefix {
+            Some(prefix_fn) => prefix_fn(self, can_assign),
+            None => {
+                self.error("Expect expression");
+                // Stands in for the missing expression; the
+                // statement it's in is dropped for the error
+                return Expr::Nil { span: self.previous.span() };
+            },
+        };
+
+        while prec <= get_parse_rule(self.current.token_type).precedence {
+            self.advance();
+            let infix_rule = get_parse_rule(self.previous.token_type);
+            match infix_rule.infix {
+                Some(infix_fn) => expr = infix_fn(self, expr, can_assign),
+                None => {
+                    // TODO: is this the error i want
+                    self.error("Expect expression");
+                    return expr;
+                },
+            }
+        }
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error("Invalid assignment target");
+        }
+        expr
+    }
+
+    // ===================================
+    // Statements
+    // ===================================
+    // A statement, or None if it had an error
+    fn declaration(&mut self) -> Option<Stmt<'a>> {
+        let errors = self.diagnostics.len();
+        let stmt = if self.match_token(TokenType::Class) {
+            Stmt::Class(self.class_declaration())
+        } else if self.match_token(TokenType::Fun) {
+            Stmt::Function(self.fun_declaration())
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        };
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+        if self.diagnostics.len() > errors {
+            return None;
+        }
+        Some(stmt)
+    }
+
+    fn class_declaration(&mut self) -> Class<'a> {
+        self.consume(TokenType::Identifier, "Expect class name");
+        let name = self.identifier();
+
+        let mut superclass = None;
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name");
+            superclass = Some(self.identifier());
+        }
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body");
+        let mut methods = vec![];
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.consume(TokenType::Identifier, "Expect method name");
+            let name = self.identifier();
+            methods.push(self.function(name));
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body");
+        Class { name, superclass, methods, right_brace: self.previous.span() }
+    }
+
+    fn fun_declaration(&mut self) -> Function<'a> {
+        self.consume(TokenType::Identifier, "Expect function name");
+        let name = self.identifier();
+        self.function(name)
+    }
+
+    // Parse a function's parameters and body
+    fn function(&mut self, name: Identifier<'a>) -> Function<'a> {
+        self.consume(TokenType::LeftParen, "Expect '(' after function name");
+        let mut params = vec![];
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if params.len() == u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters");
+                }
+                self.consume(TokenType::Identifier, "Expect parameter name");
+                params.push(self.identifier());
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body");
+        let body = self.block();
+        Function { name, params, body, right_brace: self.previous.span() }
+    }
+
+    fn var_declaration(&mut self) -> Stmt<'a> {
+        self.consume(TokenType::Identifier, "Expect variable name");
+        let name = self.identifier();
+
+        let initializer = if self.match_token(TokenType::Equal) {
+            Some(self.expression())
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration");
+
+        Stmt::Var { name, initializer, semicolon: self.previous.span() }
+    }
+
+    fn statement(&mut self) -> Stmt<'a> {
+        if self.match_token(TokenType::Print) {
+            self.print_statement()
+        } else if self.match_token(TokenType::If) {
+            self.if_statement()
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement()
+        } else if self.match_token(TokenType::While) {
+            self.while_statement()
+        } else if self.match_token(TokenType::For) {
+            self.for_statement()
+        } else if self.match_token(TokenType::LeftBrace) {
+            let left_brace = self.previous.span();
+            let statements = self.block();
+            Stmt::Block { left_brace, statements, right_brace: self.previous.span() }
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    // The declarations of a block whose '{' was just consumed
+    fn block(&mut self) -> Vec<Stmt<'a>> {
+        let mut statements = vec![];
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            statements.extend(self.declaration());
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block");
+        statements
+    }
+
+    fn if_statement(&mut self) -> Stmt<'a> {
+        let keyword = self.previous.span();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition");
+        let right_paren = self.previous.span();
+
+        let then_branch = Box::new(self.statement());
+        let else_branch = if self.match_token(TokenType::Else) {
+            Some(Box::new(self.statement()))
+        } else {
+            None
+        };
+        Stmt::If { keyword, condition, right_paren, then_branch, else_branch }
+    }
+
+    fn while_statement(&mut self) -> Stmt<'a> {
+        let keyword = self.previous.span();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition");
+        let right_paren = self.previous.span();
+
+        let body = Box::new(self.statement());
+        Stmt::While { keyword, condition, right_paren, body }
+    }
+
+    fn for_statement(&mut self) -> Stmt<'a> {
+        let keyword = self.previous.span();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'");
+        let initializer = if self.match_token(TokenType::Semicolon) {
+            None
+        } else if self.match_token(TokenType::Var) {
+            Some(Box::new(self.var_declaration()))
+        } else {
+            Some(Box::new(self.expression_statement()))
+        };
+
+        let mut condition = None;
+        if !self.match_token(TokenType::Semicolon) {
+            condition = Some(self.expression());
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition");
+        }
+        let semicolon = self.previous.span();
+
+        let mut increment = None;
+        if !self.match_token(TokenType::RightParen) {
+            increment = Some(self.expression());
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses");
+        }
+        let right_paren = self.previous.span();
+
+        let body = Box::new(self.statement());
+        Stmt::For { keyword, initializer, condition, semicolon, increment, right_paren, body }
+    }
+
+    fn return_statement(&mut self) -> Stmt<'a> {
+        let keyword = self.previous.span();
+        let value = if self.match_token(TokenType::Semicolon) {
+            None
+        } else {
+            let value = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value");
+            Some(value)
+        };
+        Stmt::Return { keyword, value, semicolon: self.previous.span() }
+    }
+
+    fn print_statement(&mut self) -> Stmt<'a> {
+        let keyword = self.previous.span();
+        let expr = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value");
+        Stmt::Print { keyword, expr, semicolon: self.previous.span() }
+    }
+
+    // An expression evaluated for its side effects; the result is discarded
+    fn expression_statement(&mut self) -> Stmt<'a> {
+        let expr = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression");
+        Stmt::Expression { expr, semicolon: self.previous.span() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::ast::{BinaryOp, Expr, Identifier, Stmt, UnaryOp};
+    use crate::chunk::Source;
+    use crate::parser::parse;
+    use crate::token::Span;
+
+    fn source(text: &str) -> Rc<Source> {
+        Rc::new(Source { name: "test.lox".to_owned(), text: text.to_owned() })
+    }
+
+    fn span(column: usize, length: usize) -> Span {
+        Span { line: 1, column, offset: column - 1, length }
+    }
+
+    #[test]
+    fn nodes_keep_their_spans() {
+        let source = source("print -a + 1;");
+        let (program, diagnostics) = parse(&source);
+        assert!(diagnostics.is_empty());
+
+        let a = Expr::Variable(Identifier { name: "a", span: span(8, 1) });
+        let negated = Expr::Unary { op: UnaryOp::Negate, op_span: span(7, 1), operand: Box::new(a) };
+        let one = Expr::Number { value: 1.0, span: span(12, 1) };
+        let sum = Expr::Binary {
+            op: BinaryOp::Add, op_span: span(10, 1), left: Box::new(negated), right: Box::new(one),
+        };
+        assert_eq!(program.statements, vec![Stmt::Print { keyword: span(1, 5), expr: sum, semicolon: span(13, 1) }]);
+        assert_eq!(program.end, span(14, 0));
+    }
+
+    #[test]
+    fn method_calls_are_calls_of_properties() {
+        let source = source("a.b(1);");
+        let (program, _) = parse(&source);
+        match &program.statements[0] {
+            Stmt::Expression { expr: Expr::Call { callee, arguments, right_paren }, .. } => {
+                assert!(matches!(**callee, Expr::Get { name: Identifier { name: "b", .. }, .. }));
+                assert_eq!(arguments.len(), 1);
+                assert_eq!(*right_paren, span(6, 1));
+            },
+            stmt => panic!("unexpected {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn statements_with_errors_are_left_out() {
+        let source = source("print 1;\nprint +;\nfun f() { print; print 2; }\nvar x;");
+        let (program, diagnostics) = parse(&source);
+        assert_eq!(diagnostics.iter().map(|d| d.span.line).collect::<Vec<_>>(), vec![2, 3]);
+        // An error anywhere in a declaration leaves out all of it
+        assert!(matches!(program.statements[..], [Stmt::Print { .. }, Stmt::Var { .. }]));
+    }
+}
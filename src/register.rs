@@ -0,0 +1,1395 @@
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::ast::{self, BinaryOp, Class, Expr, Identifier, LogicalOp, Program, Stmt, UnaryOp};
+use crate::chunk::{Chunk, Source};
+use crate::compiler::{fold_binary, fold_unary, synthetic, ClassCompiler, Diagnostic, FunctionType, Local, Upvalue,
+                      LOCALS_MAX, UPVALUES_MAX};
+use crate::heap::{Heap, Marker, Trace};
+use crate::parser;
+use crate::token::Span;
+use crate::value::{Function, Obj, Value};
+use crate::vm::{Backend, DEBUG};
+
+// The instruction set of the register backend, an alternative to the stack
+// code in `chunk`. Each call gets a window of registers on the VM's value
+// stack: register 0 holds the callee, each local has a register of its own
+// after it, and temporaries go above those. Instructions name the registers
+// they read and write, so `a = a + b` on locals is a single Add rather than
+// three pushes and a store. Operands are registers (A, B, C, S), constants
+// (K), argument counts (N), upvalues (U) and jump offsets (J), one byte
+// each except for jump offsets, which take two, and constants in the long
+// forms of instructions, which take three
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Op {
+    Move = 0,             // A B: R[A] = R[B]
+    LoadConstant = 1,     // A K
+    LoadConstantLong = 2, // A K, with a 24-bit K for big constant pools
+    LoadNil = 3,          // A
+    DefineGlobal = 4,     // A K: define the global K as R[A]
+    GetGlobal = 5,        // A K
+    SetGlobal = 6,        // A K: assign R[A] to the existing global K
+    GetUpvalue = 7,       // A U
+    SetUpvalue = 8,       // A U: assign R[A] to upvalue U
+    Close = 9,            // A: close the upvalues of R[A] and every register above it
+    Neg = 10,             // A B: R[A] = -R[B]
+    Not = 11,             // A B: R[A] = !R[B]
+    Add = 12,             // A B C: R[A] = R[B] + R[C], and likewise for the operators up to LessEqual
+    Sub = 13,
+    Mul = 14,
+    Div = 15,
+    Equal = 16,
+    NotEqual = 17,
+    Greater = 18,
+    GreaterEqual = 19,
+    Less = 20,
+    LessEqual = 21,
+    Jump = 22,            // J
+    JumpIfFalse = 23,     // A J
+    JumpIfTrue = 24,      // A J
+    Loop = 25,            // J: jump backwards
+    Print = 26,           // A
+    // A N: call R[A] with the N arguments in the registers after it, leaving the result in R[A]
+    Call = 27,
+    Invoke = 28,          // A K N: call method K of R[A], like Call
+    SuperInvoke = 29,     // A K N S: call method K of superclass R[S] on R[A], like Call
+    // A K: R[A] = a closure of function K. An (is_local, index) pair follows for each upvalue
+    Closure = 30,
+    Return = 31,          // A
+    Class = 32,           // A K: R[A] = a new class named K
+    Inherit = 33,         // A B: copy the methods of superclass R[B] into class R[A]
+    Method = 34,          // A K B: add closure R[B] to class R[A] as method K
+    GetProperty = 35,     // A B K: R[A] = R[B].K
+    SetProperty = 36,     // A K B: R[A].K = R[B]
+    GetSuper = 37,        // A B S K: R[A] = method K of superclass R[S], bound to R[B]
+    // Like the instructions they're named after, with a 24-bit K
+    DefineGlobalLong = 38,
+    GetGlobalLong = 39,
+    SetGlobalLong = 40,
+    InvokeLong = 41,
+    SuperInvokeLong = 42,
+    ClosureLong = 43,
+    ClassLong = 44,
+    MethodLong = 45,
+    GetPropertyLong = 46,
+    SetPropertyLong = 47,
+    GetSuperLong = 48,
+    Error = 255, // Any byte that isn't an opcode
+}
+
+impl Op {
+    // The form of an instruction with a constant operand that takes a 24-bit one
+    fn long(self) -> Op {
+        match self {
+            Op::LoadConstant => Op::LoadConstantLong,
+            Op::DefineGlobal => Op::DefineGlobalLong,
+            Op::GetGlobal => Op::GetGlobalLong,
+            Op::SetGlobal => Op::SetGlobalLong,
+            Op::Invoke => Op::InvokeLong,
+            Op::SuperInvoke => Op::SuperInvokeLong,
+            Op::Closure => Op::ClosureLong,
+            Op::Class => Op::ClassLong,
+            Op::Method => Op::MethodLong,
+            Op::GetProperty => Op::GetPropertyLong,
+            Op::SetProperty => Op::SetPropertyLong,
+            Op::GetSuper => Op::GetSuperLong,
+            _ => self,
+        }
+    }
+
+    pub fn is_long(self) -> bool {
+        matches!(self, Op::LoadConstantLong | Op::DefineGlobalLong | Op::GetGlobalLong | Op::SetGlobalLong
+            | Op::InvokeLong | Op::SuperInvokeLong | Op::ClosureLong | Op::ClassLong | Op::MethodLong
+            | Op::GetPropertyLong | Op::SetPropertyLong | Op::GetSuperLong)
+    }
+}
+
+impl From<Op> for u8 {
+    fn from(op: Op) -> u8 {
+        op as u8
+    }
+}
+
+// Every opcode, in encoding order
+const OPS: [Op; 49] = [
+    Op::Move, Op::LoadConstant, Op::LoadConstantLong, Op::LoadNil, Op::DefineGlobal, Op::GetGlobal,
+    Op::SetGlobal, Op::GetUpvalue, Op::SetUpvalue, Op::Close, Op::Neg, Op::Not, Op::Add, Op::Sub,
+    Op::Mul, Op::Div, Op::Equal, Op::NotEqual, Op::Greater, Op::GreaterEqual, Op::Less, Op::LessEqual,
+    Op::Jump, Op::JumpIfFalse, Op::JumpIfTrue, Op::Loop, Op::Print, Op::Call, Op::Invoke,
+    Op::SuperInvoke, Op::Closure, Op::Return, Op::Class, Op::Inherit, Op::Method, Op::GetProperty,
+    Op::SetProperty, Op::GetSuper, Op::DefineGlobalLong, Op::GetGlobalLong, Op::SetGlobalLong,
+    Op::InvokeLong, Op::SuperInvokeLong, Op::ClosureLong, Op::ClassLong, Op::MethodLong,
+    Op::GetPropertyLong, Op::SetPropertyLong, Op::GetSuperLong,
+];
+
+// What each byte decodes to, so decoding is a single load
+static DECODE: [Op; 256] = {
+    let mut table = [Op::Error; 256];
+    let mut i = 0;
+    while i < OPS.len() {
+        table[OPS[i] as usize] = OPS[i];
+        i += 1;
+    }
+    table
+};
+
+impl From<u8> for Op {
+    fn from(n: u8) -> Op {
+        DECODE[n as usize]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    Register,
+    Constant,
+    ConstantLong,
+    Count,
+    Upvalue,
+    Jump,
+}
+
+impl Operand {
+    fn len(self) -> usize {
+        match self {
+            Operand::ConstantLong => 3,
+            Operand::Jump => 2,
+            _ => 1,
+        }
+    }
+}
+
+// The operands that follow each opcode, in order
+fn operands(op: Op) -> &'static [Operand] {
+    use Operand::*;
+    match op {
+        Op::LoadNil | Op::Close | Op::Print | Op::Return => &[Register],
+        Op::Move | Op::Neg | Op::Not | Op::Inherit => &[Register, Register],
+        Op::LoadConstant | Op::DefineGlobal | Op::GetGlobal | Op::SetGlobal | Op::Closure
+        | Op::Class => &[Register, Constant],
+        Op::LoadConstantLong | Op::DefineGlobalLong | Op::GetGlobalLong | Op::SetGlobalLong
+        | Op::ClosureLong | Op::ClassLong => &[Register, ConstantLong],
+        Op::GetUpvalue | Op::SetUpvalue => &[Register, Upvalue],
+        Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Equal | Op::NotEqual | Op::Greater
+        | Op::GreaterEqual | Op::Less | Op::LessEqual => &[Register, Register, Register],
+        Op::Jump | Op::Loop => &[Jump],
+        Op::JumpIfFalse | Op::JumpIfTrue => &[Register, Jump],
+        Op::Call => &[Register, Count],
+        Op::Invoke => &[Register, Constant, Count],
+        Op::InvokeLong => &[Register, ConstantLong, Count],
+        Op::SuperInvoke => &[Register, Constant, Count, Register],
+        Op::SuperInvokeLong => &[Register, ConstantLong, Count, Register],
+        Op::Method | Op::SetProperty => &[Register, Constant, Register],
+        Op::MethodLong | Op::SetPropertyLong => &[Register, ConstantLong, Register],
+        Op::GetProperty => &[Register, Register, Constant],
+        Op::GetPropertyLong => &[Register, Register, ConstantLong],
+        Op::GetSuper => &[Register, Register, Register, Constant],
+        Op::GetSuperLong => &[Register, Register, Register, ConstantLong],
+        Op::Error => &[],
+    }
+}
+
+// How many upvalues the Closure instruction at `offset` captures
+fn closure_upvalue_count(chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
+    let (addr, _) = chunk.read_constant(offset + 2, Op::from(chunk.code[offset]).is_long());
+    match chunk.constants[addr].as_obj().map(|obj| heap.get(obj)) {
+        Some(Obj::Function(function)) => function.upvalue_count,
+        _ => 0,
+    }
+}
+
+// The length of the instruction at `offset`, including its operands
+pub fn instruction_len(chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
+    let op = Op::from(chunk.code[offset]);
+    let len = 1 + operands(op).iter().map(|operand| operand.len()).sum::<usize>();
+    match op {
+        Op::Closure | Op::ClosureLong => len + 2 * closure_upvalue_count(chunk, offset, heap),
+        _ => len,
+    }
+}
+
+pub fn disassemble(chunk: &Chunk, name: &str, heap: &Heap) {
+    println!("== {} ==", name);
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(chunk, offset, heap);
+    }
+}
+
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
+    print!("{:04} ", offset);
+    if offset > 0 && chunk.line_at(offset) == chunk.line_at(offset - 1) {
+        print!("   | ");
+    } else {
+        print!("{:4} ", chunk.line_at(offset));
+    }
+    let op = Op::from(chunk.code[offset]);
+    if op == Op::Error {
+        println!("INVALID OPCODE");
+        return usize::MAX;
+    }
+
+    let mut line = format!("{:16}", format!("{:?}", op));
+    let mut at = offset + 1;
+    for &operand in operands(op) {
+        line += &match operand {
+            Operand::Register => format!(" r{}", chunk.code[at]),
+            Operand::Constant | Operand::ConstantLong => {
+                let (addr, _) = chunk.read_constant(at, operand == Operand::ConstantLong);
+                format!(" {} '{}'", addr, chunk.constants[addr].display(heap))
+            },
+            Operand::Count => format!(" ({} args)", chunk.code[at]),
+            Operand::Upvalue => format!(" u{}", chunk.code[at]),
+            Operand::Jump => {
+                // Jumps are the last operand, and count from the next instruction
+                let jump = chunk.read_u16(at) as usize;
+                let target = if op == Op::Loop { at + 2 - jump } else { at + 2 + jump };
+                format!(" -> {}", target)
+            },
+        };
+        at += operand.len();
+    }
+    println!("{}", line);
+
+    if op == Op::Closure {
+        for _ in 0..closure_upvalue_count(chunk, offset, heap) {
+            let kind = if chunk.code[at] == 1 { "local" } else { "upvalue" };
+            println!("{:04}    |                     {} {}", at, kind, chunk.code[at + 1]);
+            at += 2;
+        }
+    }
+    at
+}
+
+// The most registers a call's window can have with one-byte operands
+const REGISTERS_MAX: usize = u8::MAX as usize + 1;
+
+// Where a variable lives
+#[derive(Clone, Copy)]
+enum Variable {
+    Local(u8), // The register of a local of the function being compiled
+    Upvalue(u8),
+    Global(usize), // The constant holding its name
+}
+
+// Per-function compilation state
+struct Compiler<'a> {
+    function: Function,
+    function_type: FunctionType,
+    // Locals in scope. Each lives in the register of its index
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize, // 0 is global scope
+    // Registers from here up are free. Those between the
+    // locals and here hold temporaries still in use
+    next_register: usize,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(function_type: FunctionType, name: Option<String>, source: Rc<Source>) -> Compiler<'a> {
+        let mut locals = Vec::with_capacity(LOCALS_MAX);
+        // Register 0 holds the receiver in methods, and otherwise the
+        // function being called, as slot 0 does for stack code
+        let register_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        locals.push(Local { name: register_zero, depth: Some(0), is_captured: false });
+        Compiler {
+            function: Function { arity: 0, upvalue_count: 0, registers: 1, backend: Backend::Register,
+                                 chunk: Chunk::with_source(source), name },
+            function_type,
+            locals,
+            upvalues: vec![],
+            scope_depth: 0,
+            next_register: 1,
+        }
+    }
+}
+
+// Everything that must survive a collection during compilation: the
+// constants of every function still being compiled, and whatever the VM holds
+struct CompilerRoots<'r, 'a> {
+    compilers: &'r [Compiler<'a>],
+    vm: &'r dyn Trace,
+}
+
+impl Trace for CompilerRoots<'_, '_> {
+    fn trace(&self, marker: &mut Marker) {
+        for compiler in self.compilers {
+            compiler.function.trace(marker);
+        }
+        self.vm.trace(marker);
+    }
+}
+
+// Lowers a program's syntax tree to register code. It reports the
+// same errors as the stack backend's `CodeGenerator`, in the same order
+pub struct RegisterGenerator<'a> {
+    source: Rc<Source>, // What's being compiled, for error reports
+    diagnostics: Vec<Diagnostic>, // Every error found so far
+    // Set after an error until the end of the statement,
+    // so one mistake is only reported once
+    panic_mode: bool,
+    // One compiler per function being compiled, innermost last
+    compilers: Vec<Compiler<'a>>,
+    // One per class being compiled, innermost last
+    class_compilers: Vec<ClassCompiler>,
+    heap: &'a mut Heap, // Where constants are allocated
+    roots: &'a dyn Trace, // The VM's roots, for collections during compilation
+}
+
+impl<'a> RegisterGenerator<'a> {
+    pub fn new(source: Rc<Source>, heap: &'a mut Heap, roots: &'a dyn Trace) -> RegisterGenerator<'a> {
+        RegisterGenerator {
+            source: Rc::clone(&source),
+            diagnostics: vec![],
+            panic_mode: false,
+            compilers: vec![Compiler::new(FunctionType::Script, None, source)],
+            class_compilers: vec![],
+            heap,
+            roots,
+        }
+    }
+
+    // Compile a program into the function that runs its top-level code
+    pub fn program(&mut self, program: &Program<'a>) -> Function {
+        for stmt in &program.statements {
+            self.declaration(stmt);
+        }
+        let (function, _) = self.end_compiler(program.end);
+        function
+    }
+
+    fn compiler(&mut self) -> &mut Compiler<'a> {
+        self.compilers.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler().function.chunk
+    }
+
+    fn error(&mut self, span: Span, message: &str) {
+        if self.panic_mode { return; }
+        self.panic_mode = true;
+        let diagnostic = Diagnostic::at(message, &self.source, span);
+        self.diagnostics.push(diagnostic);
+    }
+
+    // ===================================
+    // Registers
+    // ===================================
+    // Take the lowest free register
+    fn reserve(&mut self, span: Span) -> u8 {
+        let compiler = self.compiler();
+        let register = compiler.next_register;
+        if register == REGISTERS_MAX {
+            self.error(span, "Too many registers in use in function");
+            return u8::MAX;
+        }
+        compiler.next_register += 1;
+        compiler.function.registers = compiler.function.registers.max(compiler.next_register);
+        register as u8
+    }
+
+    // Free every register taken since `next_register` was `mark`
+    fn free(&mut self, mark: usize) {
+        self.compiler().next_register = mark;
+    }
+
+    fn mark(&mut self) -> usize {
+        self.compiler().next_register
+    }
+
+    // Free every temporary. Only statements hold none
+    fn free_temporaries(&mut self) {
+        let compiler = self.compiler();
+        compiler.next_register = compiler.locals.len();
+    }
+
+    // ===================================
+    // Variables
+    // ===================================
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let value = Value::obj(self.heap.intern(name));
+        self.add_constant(value)
+    }
+
+    // Find the register of a local variable of the function compiled
+    // by `self.compilers[compiler]`, searching innermost first
+    fn resolve_local(&mut self, compiler: usize, name: Identifier) -> Option<u8> {
+        let (i, local) = self.compilers[compiler].locals.iter().enumerate().rev()
+            .find(|(_, local)| local.name == name.name)?;
+        let initialized = local.depth.is_some();
+        if !initialized {
+            self.error(name.span, "Can't read local variable in its own initializer");
+        }
+        Some(i as u8)
+    }
+
+    fn add_upvalue(&mut self, compiler: usize, upvalue: Upvalue, span: Span) -> u8 {
+        let upvalues = &self.compilers[compiler].upvalues;
+        // Closures capture each variable only once
+        if let Some(i) = upvalues.iter().position(|&existing| existing == upvalue) {
+            return i as u8;
+        }
+
+        if upvalues.len() == UPVALUES_MAX {
+            self.error(span, "Too many closure variables in function");
+            return 0;
+        }
+
+        let compiler = &mut self.compilers[compiler];
+        compiler.upvalues.push(upvalue);
+        compiler.function.upvalue_count = compiler.upvalues.len();
+        (compiler.upvalues.len() - 1) as u8
+    }
+
+    // Find the upvalue index of a variable declared in a function
+    // enclosing the one compiled by `self.compilers[compiler]`,
+    // threading it through every function in between
+    fn resolve_upvalue(&mut self, compiler: usize, name: Identifier) -> Option<u8> {
+        if compiler == 0 {
+            return None;
+        }
+        let enclosing = compiler - 1;
+
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.compilers[enclosing].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(compiler, Upvalue { index: local, is_local: true }, name.span));
+        }
+
+        let upvalue = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(compiler, Upvalue { index: upvalue, is_local: false }, name.span))
+    }
+
+    fn resolve(&mut self, name: Identifier) -> Variable {
+        let innermost = self.compilers.len() - 1;
+        if let Some(register) = self.resolve_local(innermost, name) {
+            Variable::Local(register)
+        } else if let Some(index) = self.resolve_upvalue(innermost, name) {
+            Variable::Upvalue(index)
+        } else {
+            Variable::Global(self.identifier_constant(name.name))
+        }
+    }
+
+    // Copy a variable's value into `dst`
+    fn load(&mut self, variable: Variable, dst: u8, span: Span) {
+        match variable {
+            Variable::Local(register) => self.emit_move(dst, register, span),
+            Variable::Upvalue(index) => self.emit(&[Op::GetUpvalue.into(), dst, index], span),
+            // Only globals can be undefined at runtime, so only they
+            // need to point back at the name for error reports
+            Variable::Global(name) => self.emit_with_constant(Op::GetGlobal, &[dst], name, &[], span),
+        }
+    }
+
+    // A register holding the variable `name`: its own if it's a local
+    fn variable_operand(&mut self, name: Identifier) -> u8 {
+        let variable = self.resolve(name);
+        self.register_of(variable, name.span)
+    }
+
+    fn register_of(&mut self, variable: Variable, span: Span) -> u8 {
+        match variable {
+            Variable::Local(register) => register,
+            variable => {
+                let register = self.reserve(span);
+                self.load(variable, register, span);
+                register
+            },
+        }
+    }
+
+    // Assign `value` to the variable `name`, returning the register left holding it
+    fn assign(&mut self, name: Identifier, value: &Expr<'a>) -> u8 {
+        match self.resolve(name) {
+            Variable::Local(register) if writes_once(value) => {
+                self.expression(value, register);
+                register
+            },
+            Variable::Local(register) => {
+                let mark = self.mark();
+                let source = self.operand(value);
+                self.emit_move(register, source, name.span);
+                self.free(mark);
+                register
+            },
+            Variable::Upvalue(index) => {
+                let source = self.operand(value);
+                self.emit(&[Op::SetUpvalue.into(), source, index], name.span);
+                source
+            },
+            Variable::Global(global) => {
+                let source = self.operand(value);
+                self.emit_with_constant(Op::SetGlobal, &[source], global, &[], name.span);
+                source
+            },
+        }
+    }
+
+    fn add_local(&mut self, name: &'a str, span: Span) {
+        if self.compiler().locals.len() == LOCALS_MAX {
+            self.error(span, "Too many local variables in function");
+            return;
+        }
+        self.compiler().locals.push(Local { name, depth: None, is_captured: false });
+    }
+
+    // Record a new local in the current scope. Globals are late bound,
+    // so there is nothing to record for them
+    fn declare_variable(&mut self, name: Identifier<'a>) {
+        if self.compiler().scope_depth == 0 {
+            return;
+        }
+
+        let scope_depth = self.compiler().scope_depth;
+        let already_declared = self.compiler().locals.iter().rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == name.name);
+        if already_declared {
+            self.error(name.span, "Already a variable with this name in this scope");
+        }
+
+        self.add_local(name.name, name.span);
+    }
+
+    fn mark_initialized(&mut self) {
+        let compiler = self.compiler();
+        if compiler.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = compiler.locals.last_mut() {
+            local.depth = Some(compiler.scope_depth);
+        }
+    }
+
+    // ===================================
+    // Expressions
+    // ===================================
+    // Compile `expr`, leaving its value in `dst`. Nothing may read
+    // `dst` while `expr` runs, as it may hold partial results meanwhile
+    fn expression(&mut self, expr: &Expr<'a>, dst: u8) {
+        // Folding operations on constants also saves the temporaries
+        // their operands would need, however deeply they nest
+        if let Expr::Unary { .. } | Expr::Binary { .. } = expr {
+            if let Some(value) = self.constant(expr) {
+                self.load_constant(value, dst, expr.last_span());
+                return;
+            }
+        }
+
+        match expr {
+            Expr::Number { value, span } => self.load_constant(Value::number(*value), dst, *span),
+            Expr::String { value, span } => {
+                let interned = self.heap.intern(value);
+                self.load_constant(Value::obj(interned), dst, *span);
+            },
+            Expr::Bool { value, span } => self.load_constant(Value::bool(*value), dst, *span),
+            Expr::Nil { span } => self.load_constant(Value::NIL, dst, *span),
+            Expr::Variable(name) => {
+                let variable = self.resolve(*name);
+                self.load(variable, dst, name.span);
+            },
+            Expr::Assign { name, value } => {
+                let mark = self.mark();
+                let source = self.assign(*name, value);
+                self.emit_move(dst, source, name.span);
+                self.free(mark);
+            },
+            Expr::This { span } => {
+                let this = self.this(*span);
+                self.load(this, dst, *span);
+            },
+            Expr::Super { keyword, method } => self.super_(*keyword, *method, None, dst),
+            Expr::Unary { op, op_span, operand } => {
+                let mark = self.mark();
+                let operand = self.operand(operand);
+                // Runtime errors point at the operator, not the operand
+                let op = match op {
+                    UnaryOp::Negate => Op::Neg,
+                    UnaryOp::Not => Op::Not,
+                };
+                self.emit(&[op.into(), dst, operand], *op_span);
+                self.free(mark);
+            },
+            Expr::Binary { .. } => self.binary(expr, dst),
+            Expr::Logical { op, op_span, left, right } => {
+                // Short-circuit: the left operand is the result if it decides it
+                self.expression(left, dst);
+                let jump = match op {
+                    LogicalOp::And => Op::JumpIfFalse,
+                    LogicalOp::Or => Op::JumpIfTrue,
+                };
+                let end_jump = self.emit_jump(&[jump.into(), dst], *op_span);
+                self.expression(right, dst);
+                self.patch_jump(end_jump, *op_span);
+            },
+            Expr::Grouping { expr, .. } => self.expression(expr, dst),
+            Expr::Call { callee, arguments, right_paren } => self.call(callee, arguments, *right_paren, dst),
+            Expr::Get { object, name } => {
+                let mark = self.mark();
+                let object = self.operand(object);
+                let name_constant = self.identifier_constant(name.name);
+                self.emit_with_constant(Op::GetProperty, &[dst, object], name_constant, &[], name.span);
+                self.free(mark);
+            },
+            Expr::Set { object, name, value } => {
+                let mark = self.mark();
+                // The object can only be read from a local's register
+                // if evaluating the value can't assign to that local
+                let object = if is_pure(value) {
+                    self.operand(object)
+                } else {
+                    self.temporary(object)
+                };
+                let name_constant = self.identifier_constant(name.name);
+                self.expression(value, dst);
+                self.emit_with_constant(Op::SetProperty, &[object], name_constant, &[dst], name.span);
+                self.free(mark);
+            },
+        }
+    }
+
+    // Compile `expr` and return a register holding its value. That's the
+    // variable's own register for a local, so reading one needs no move.
+    // Otherwise it's a new temporary, which the caller frees
+    fn operand(&mut self, expr: &Expr<'a>) -> u8 {
+        match expr {
+            Expr::Variable(name) => self.variable_operand(*name),
+            Expr::Assign { name, value } => self.assign(*name, value),
+            Expr::This { span } => {
+                let this = self.this(*span);
+                self.register_of(this, *span)
+            },
+            Expr::Grouping { expr, .. } => self.operand(expr),
+            _ => self.temporary(expr),
+        }
+    }
+
+    // The value of `expr` if it can be computed at compile time, as the
+    // stack compiler folds it
+    fn constant(&mut self, expr: &Expr<'a>) -> Option<Value> {
+        match expr {
+            Expr::Number { value, .. } => Some(Value::number(*value)),
+            Expr::String { value, .. } => Some(Value::obj(self.heap.intern(value))),
+            Expr::Bool { value, .. } => Some(Value::bool(*value)),
+            Expr::Nil { .. } => Some(Value::NIL),
+            Expr::Unary { op, operand, .. } => fold_unary(*op, self.constant(operand)?),
+            Expr::Binary { .. } => {
+                // Walk left-nested chains iteratively, as `binary` does
+                let mut operations = vec![];
+                let mut left = expr;
+                while let Expr::Binary { op, left: inner, right, .. } = left {
+                    operations.push((*op, &**right));
+                    left = inner;
+                }
+                let mut value = self.constant(left)?;
+                for (op, right) in operations.into_iter().rev() {
+                    let right = self.constant(right)?;
+                    value = fold_binary(self.heap, op, value, right)?;
+                }
+                Some(value)
+            },
+            Expr::Grouping { expr, .. } => self.constant(expr),
+            _ => None,
+        }
+    }
+
+    // Compile `expr` into a new temporary
+    fn temporary(&mut self, expr: &Expr<'a>) -> u8 {
+        let register = self.reserve(expr.last_span());
+        self.expression(expr, register);
+        register
+    }
+
+    fn binary(&mut self, expr: &Expr<'a>, dst: u8) {
+        // Chains like `a + b + c` nest to the left. Walk down them
+        // iteratively so long ones don't need a stack frame per operator
+        let mut operations = vec![];
+        let mut left = expr;
+        while let Expr::Binary { op, op_span, left: inner, right } = left {
+            operations.push((*op, *op_span, &**right));
+            left = inner;
+        }
+
+        let mark = self.mark();
+        // The leftmost operand can only be read from a local's register if
+        // evaluating the first right operand can't assign to that local.
+        // Every later operation reads the result of the one before from `dst`
+        let (_, _, first_right) = operations.last().unwrap();
+        let mut lhs = if is_pure(first_right) {
+            self.operand(left)
+        } else {
+            self.expression(left, dst);
+            dst
+        };
+        for (op, op_span, right) in operations.into_iter().rev() {
+            let rhs = self.operand(right);
+            // Runtime errors point at the operator, not the right operand
+            let op = match op {
+                BinaryOp::Add => Op::Add,
+                BinaryOp::Sub => Op::Sub,
+                BinaryOp::Mul => Op::Mul,
+                BinaryOp::Div => Op::Div,
+                BinaryOp::Equal => Op::Equal,
+                BinaryOp::NotEqual => Op::NotEqual,
+                BinaryOp::Greater => Op::Greater,
+                BinaryOp::GreaterEqual => Op::GreaterEqual,
+                BinaryOp::Less => Op::Less,
+                BinaryOp::LessEqual => Op::LessEqual,
+            };
+            self.emit(&[op.into(), dst, lhs, rhs], op_span);
+            self.free(mark);
+            lhs = dst;
+        }
+    }
+
+    // Where to put a call's callee, given that its result goes in `dst`.
+    // The arguments go in the registers after the callee, so it must be
+    // the highest register in use
+    fn call_base(&mut self, dst: u8, span: Span) -> u8 {
+        if dst as usize + 1 == self.mark() {
+            dst
+        } else {
+            self.reserve(span)
+        }
+    }
+
+    // Compile the arguments of a call into the registers after its callee
+    fn arguments(&mut self, arguments: &[Expr<'a>]) -> u8 {
+        for argument in arguments {
+            let register = self.reserve(argument.last_span());
+            let mark = self.mark();
+            self.expression(argument, register);
+            self.free(mark);
+        }
+        arguments.len() as u8
+    }
+
+    fn call(&mut self, callee: &Expr<'a>, arguments: &[Expr<'a>], right_paren: Span, dst: u8) {
+        let mark = self.mark();
+        match callee {
+            // Call the method directly instead of creating a bound method
+            Expr::Get { object, name } => {
+                let base = self.call_base(dst, name.span);
+                self.expression(object, base);
+                let name_constant = self.identifier_constant(name.name);
+                let arg_count = self.arguments(arguments);
+                self.emit_with_constant(Op::Invoke, &[base], name_constant, &[arg_count], name.span);
+                self.emit_move(dst, base, right_paren);
+            },
+            Expr::Super { keyword, method } => self.super_(*keyword, *method, Some((arguments, right_paren)), dst),
+            _ => {
+                let base = self.call_base(dst, right_paren);
+                self.expression(callee, base);
+                let arg_count = self.arguments(arguments);
+                self.emit(&[Op::Call.into(), base, arg_count], right_paren);
+                self.emit_move(dst, base, right_paren);
+            },
+        }
+        self.free(mark);
+    }
+
+    // `super.method`, or a call to it with `call`'s arguments
+    fn super_(&mut self, keyword: Span, method: Identifier, call: Option<(&[Expr<'a>], Span)>, dst: u8) {
+        match self.class_compilers.last() {
+            None => self.error(keyword, "Can't use 'super' outside of a class"),
+            Some(class) if !class.has_superclass => {
+                self.error(keyword, "Can't use 'super' in a class with no superclass");
+            },
+            _ => (),
+        }
+        let name = self.identifier_constant(method.name);
+
+        // The method is looked up on the superclass statically, but bound to `this`
+        let mark = self.mark();
+        if let Some((arguments, right_paren)) = call {
+            let base = self.call_base(dst, method.span);
+            let this = self.resolve(synthetic("this"));
+            self.load(this, base, method.span);
+            let arg_count = self.arguments(arguments);
+            let superclass = self.variable_operand(synthetic("super"));
+            self.emit_with_constant(Op::SuperInvoke, &[base], name, &[arg_count, superclass], method.span);
+            self.emit_move(dst, base, right_paren);
+        } else {
+            let this = self.variable_operand(synthetic("this"));
+            let superclass = self.variable_operand(synthetic("super"));
+            self.emit_with_constant(Op::GetSuper, &[dst, this, superclass], name, &[], method.span);
+        }
+        self.free(mark);
+    }
+
+    fn this(&mut self, span: Span) -> Variable {
+        if self.class_compilers.is_empty() {
+            self.error(span, "Can't use 'this' outside of a class");
+            return Variable::Local(0);
+        }
+        self.resolve(Identifier { name: "this", span })
+    }
+
+    // ===================================
+    // Statements
+    // ===================================
+    // A statement in a block, function body, or at the top level
+    fn declaration(&mut self, stmt: &Stmt<'a>) {
+        self.statement(stmt);
+        self.panic_mode = false;
+        self.free_temporaries();
+    }
+
+    fn statement(&mut self, stmt: &Stmt<'a>) {
+        match stmt {
+            Stmt::Expression { expr, .. } => {
+                let mark = self.mark();
+                self.operand(expr);
+                self.free(mark);
+            },
+            Stmt::Print { expr, semicolon, .. } => {
+                let mark = self.mark();
+                let value = self.operand(expr);
+                self.emit(&[Op::Print.into(), value], *semicolon);
+                self.free(mark);
+            },
+            Stmt::Var { name, initializer, semicolon } => {
+                self.var_declaration(*name, initializer.as_ref(), *semicolon);
+            },
+            Stmt::Function(function) => self.fun_declaration(function),
+            Stmt::Class(class) => self.class_declaration(class),
+            Stmt::Block { statements, right_brace, .. } => {
+                self.begin_scope();
+                for stmt in statements {
+                    self.declaration(stmt);
+                }
+                self.end_scope(*right_brace);
+            },
+            Stmt::If { condition, right_paren, then_branch, else_branch, .. } => {
+                self.if_statement(condition, *right_paren, then_branch, else_branch.as_deref());
+            },
+            Stmt::While { condition, right_paren, body, .. } => {
+                self.while_statement(condition, *right_paren, body);
+            },
+            Stmt::For { initializer, condition, semicolon, increment, right_paren, body, .. } => {
+                self.for_statement(initializer.as_deref(), condition.as_ref(), *semicolon,
+                                   increment.as_ref(), *right_paren, body);
+            },
+            Stmt::Return { keyword, value, semicolon } => {
+                self.return_statement(*keyword, value.as_ref(), *semicolon);
+            },
+        }
+    }
+
+    fn class_declaration(&mut self, class: &Class<'a>) {
+        let name = class.name;
+        let name_constant = self.identifier_constant(name.name);
+        self.declare_variable(name);
+
+        // A local class is created in its own register, a global one in a temporary
+        let register = self.reserve(name.span);
+        self.emit_with_constant(Op::Class, &[register], name_constant, &[], name.span);
+        if self.compiler().scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            self.emit_with_constant(Op::DefineGlobal, &[register], name_constant, &[], name.span);
+        }
+        self.free_temporaries();
+
+        self.class_compilers.push(ClassCompiler { has_superclass: false });
+
+        if let Some(superclass) = class.superclass {
+            let register = self.reserve(superclass.span);
+            let variable = self.resolve(superclass);
+            self.load(variable, register, superclass.span);
+
+            if name.name == superclass.name {
+                self.error(superclass.span, "A class can't inherit from itself");
+            }
+
+            // Methods find the superclass in a local named `super`, in a
+            // scope of its own so each subclass captures its own superclass.
+            // It's the register the superclass was just loaded into
+            self.begin_scope();
+            self.add_local("super", superclass.span);
+            self.mark_initialized();
+
+            let class_register = self.variable_operand(name);
+            self.emit(&[Op::Inherit.into(), class_register, register], superclass.span);
+            self.free_temporaries();
+            self.class_compilers.last_mut().unwrap().has_superclass = true;
+        }
+
+        let class_register = self.variable_operand(name);
+        for method in &class.methods {
+            self.method(method, class_register);
+        }
+        self.free_temporaries();
+
+        if self.class_compilers.pop().unwrap().has_superclass {
+            self.end_scope(class.right_brace);
+        }
+    }
+
+    fn method(&mut self, method: &ast::Function<'a>, class: u8) {
+        let name_constant = self.identifier_constant(method.name.name);
+
+        let function_type = if method.name.name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        let mark = self.mark();
+        let closure = self.reserve(method.name.span);
+        self.function(method, function_type, closure);
+
+        self.emit_with_constant(Op::Method, &[class], name_constant, &[closure], method.right_brace);
+        self.free(mark);
+    }
+
+    fn fun_declaration(&mut self, function: &ast::Function<'a>) {
+        let name = function.name;
+        if self.compiler().scope_depth > 0 {
+            self.declare_variable(name);
+            let register = self.reserve(name.span);
+            // A function may refer to itself, so it's initialized before its body
+            self.mark_initialized();
+            self.function(function, FunctionType::Function, register);
+        } else {
+            let global = self.identifier_constant(name.name);
+            let register = self.reserve(name.span);
+            self.function(function, FunctionType::Function, register);
+            self.emit_with_constant(Op::DefineGlobal, &[register], global, &[], function.right_brace);
+        }
+    }
+
+    // Compile a function's parameters and body, leaving a closure of it in `dst`
+    fn function(&mut self, function: &ast::Function<'a>, function_type: FunctionType, dst: u8) {
+        let name = function.name.name.to_owned();
+        let source = Rc::clone(&self.source);
+        self.compilers.push(Compiler::new(function_type, Some(name), source));
+        // The function's body scope is never ended; its locals are
+        // discarded along with the call frame when it returns
+        self.begin_scope();
+
+        // Arguments arrive in the registers after the callee
+        for &param in &function.params {
+            self.compiler().function.arity += 1;
+            self.declare_variable(param);
+            self.reserve(param.span);
+            self.mark_initialized();
+        }
+        for stmt in &function.body {
+            self.declaration(stmt);
+        }
+
+        let (function_object, upvalues) = self.end_compiler(function.right_brace);
+        let function_object = self.heap.alloc(Obj::Function(Rc::new(function_object)));
+        let constant = self.add_constant(Value::obj(function_object));
+        self.emit_with_constant(Op::Closure, &[dst], constant, &[], function.right_brace);
+
+        // Tell the VM where to capture each upvalue from
+        for upvalue in upvalues {
+            self.emit(&[upvalue.is_local as u8, upvalue.index], function.right_brace);
+        }
+    }
+
+    fn var_declaration(&mut self, name: Identifier<'a>, initializer: Option<&Expr<'a>>, semicolon: Span) {
+        if self.compiler().scope_depth > 0 {
+            // The local's register is the next free one
+            self.declare_variable(name);
+            let register = self.reserve(name.span);
+            match initializer {
+                Some(initializer) => self.expression(initializer, register),
+                None => self.emit(&[Op::LoadNil.into(), register], semicolon),
+            }
+            self.mark_initialized();
+            return;
+        }
+
+        let global = self.identifier_constant(name.name);
+        let value = match initializer {
+            Some(initializer) => self.operand(initializer),
+            None => {
+                let register = self.reserve(semicolon);
+                self.emit(&[Op::LoadNil.into(), register], semicolon);
+                register
+            },
+        };
+        self.emit_with_constant(Op::DefineGlobal, &[value], global, &[], semicolon);
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self, span: Span) {
+        self.compiler().scope_depth -= 1;
+
+        // Forget the locals that belonged to the scope we're leaving. Their
+        // registers are free to reuse, once any that closures captured
+        // have been moved to the heap
+        let scope_depth = self.compiler().scope_depth;
+        let mut lowest_captured = None;
+        while let Some(local) = self.compiler().locals.last() {
+            if local.depth.is_some_and(|depth| depth <= scope_depth) {
+                break;
+            }
+            if local.is_captured {
+                lowest_captured = Some(self.compiler().locals.len() - 1);
+            }
+            self.compiler().locals.pop();
+        }
+        if let Some(register) = lowest_captured {
+            self.emit(&[Op::Close.into(), register as u8], span);
+        }
+        self.free_temporaries();
+    }
+
+    // Compile a condition and a jump past the code that follows it if it's falsey
+    fn condition(&mut self, condition: &Expr<'a>, span: Span) -> usize {
+        let mark = self.mark();
+        let value = self.operand(condition);
+        self.free(mark);
+        self.emit_jump(&[Op::JumpIfFalse.into(), value], span)
+    }
+
+    fn if_statement(&mut self, condition: &Expr<'a>, right_paren: Span,
+                    then_branch: &Stmt<'a>, else_branch: Option<&Stmt<'a>>) {
+        let then_jump = self.condition(condition, right_paren);
+        self.statement(then_branch);
+
+        match else_branch {
+            Some(else_branch) => {
+                let else_jump = self.emit_jump(&[Op::Jump.into()], right_paren);
+                self.patch_jump(then_jump, right_paren);
+                self.statement(else_branch);
+                self.patch_jump(else_jump, right_paren);
+            },
+            None => self.patch_jump(then_jump, right_paren),
+        }
+    }
+
+    fn while_statement(&mut self, condition: &Expr<'a>, right_paren: Span, body: &Stmt<'a>) {
+        let loop_start = self.chunk().code.len();
+        let exit_jump = self.condition(condition, right_paren);
+        self.statement(body);
+        self.emit_loop(loop_start, right_paren);
+        self.patch_jump(exit_jump, right_paren);
+    }
+
+    fn for_statement(&mut self, initializer: Option<&Stmt<'a>>, condition: Option<&Expr<'a>>, semicolon: Span,
+                     increment: Option<&Expr<'a>>, right_paren: Span, body: &Stmt<'a>) {
+        // Variables declared in the initializer are scoped to the loop
+        self.begin_scope();
+        let mut loop_variable = None;
+        match initializer {
+            None => (),
+            Some(Stmt::Var { name, initializer, semicolon }) => {
+                self.var_declaration(*name, initializer.as_ref(), *semicolon);
+                let register = self.compiler().locals.len() - 1;
+                loop_variable = Some((register as u8, self.compiler().locals[register].name));
+            },
+            Some(initializer) => self.statement(initializer),
+        }
+        self.free_temporaries();
+
+        let mut loop_start = self.chunk().code.len();
+        let exit_jump = condition.map(|condition| self.condition(condition, semicolon));
+
+        // The increment is compiled before the body, as the stack backend
+        // does, so errors are found in the same order. It runs after the
+        // body, so jump over it now and loop back to it from the end of the body
+        if let Some(increment) = increment {
+            let body_jump = self.emit_jump(&[Op::Jump.into()], semicolon);
+            let increment_start = self.chunk().code.len();
+            let mark = self.mark();
+            self.operand(increment);
+            self.free(mark);
+
+            self.emit_loop(loop_start, right_paren);
+            loop_start = increment_start;
+            self.patch_jump(body_jump, right_paren);
+        }
+
+        match loop_variable {
+            Some((register, name)) => self.loop_body_with_copy(register, name, body, right_paren),
+            None => self.statement(body),
+        }
+        self.emit_loop(loop_start, right_paren);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump, right_paren);
+        }
+
+        self.end_scope(right_paren);
+    }
+
+    // Compile a `for` body that sees a fresh copy of the loop variable, so
+    // closures created in different iterations capture different variables
+    fn loop_body_with_copy(&mut self, register: u8, name: &'a str, body: &Stmt<'a>, right_paren: Span) {
+        self.begin_scope();
+        self.add_local(name, right_paren);
+        let copy = self.reserve(right_paren);
+        self.mark_initialized();
+        self.emit_move(copy, register, right_paren);
+
+        self.statement(body);
+
+        // Write the copy back so the increment clause sees body assignments
+        self.emit_move(register, copy, right_paren);
+        self.end_scope(right_paren);
+    }
+
+    fn return_statement(&mut self, keyword: Span, value: Option<&Expr<'a>>, semicolon: Span) {
+        if self.compiler().function_type == FunctionType::Script {
+            self.error(keyword, "Can't return from top-level code");
+        }
+
+        match value {
+            None => self.emit_return(semicolon),
+            Some(value) => {
+                if self.compiler().function_type == FunctionType::Initializer {
+                    self.error(keyword, "Can't return a value from an initializer");
+                }
+
+                let mark = self.mark();
+                let value = self.operand(value);
+                self.emit(&[Op::Return.into(), value], semicolon);
+                self.free(mark);
+            },
+        }
+    }
+
+    // ===================================
+    // Emitting code
+    // ===================================
+    // Emit an instruction, blaming any runtime error in it on `span`
+    fn emit(&mut self, bytes: &[u8], span: Span) {
+        for &byte in bytes {
+            self.chunk().write(byte, span);
+        }
+    }
+
+    fn emit_move(&mut self, dst: u8, src: u8, span: Span) {
+        if dst != src {
+            self.emit(&[Op::Move.into(), dst, src], span);
+        }
+    }
+
+    // Functions without an explicit return value return nil,
+    // except initializers, which return the new instance
+    fn emit_return(&mut self, span: Span) {
+        if self.compiler().function_type == FunctionType::Initializer {
+            self.emit(&[Op::Return.into(), 0], span);
+        } else {
+            let mark = self.mark();
+            let register = self.reserve(span);
+            self.emit(&[Op::LoadNil.into(), register, Op::Return.into(), register], span);
+            self.free(mark);
+        }
+    }
+
+    // Finish the innermost function and return it with its upvalues
+    fn end_compiler(&mut self, span: Span) -> (Function, Vec<Upvalue>) {
+        self.emit_return(span);
+        let compiler = self.compilers.pop().unwrap();
+
+        if DEBUG && self.diagnostics.is_empty() {
+            let name = compiler.function.name.as_deref().unwrap_or("<script>");
+            disassemble(&compiler.function.chunk, name, self.heap);
+        }
+
+        (compiler.function, compiler.upvalues)
+    }
+
+    // Emit a jump with a placeholder offset, returning the
+    // location of the offset so it can be patched later
+    fn emit_jump(&mut self, instruction: &[u8], span: Span) -> usize {
+        self.emit(instruction, span);
+        self.emit(&[0xff, 0xff], span);
+        self.chunk().code.len() - 2
+    }
+
+    // Point the jump whose offset is at `offset` to the next instruction
+    fn patch_jump(&mut self, offset: usize, span: Span) {
+        // -2 to adjust for the jump offset itself
+        let jump = self.chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error(span, "Too much code to jump over");
+        }
+
+        self.chunk().code[offset] = (jump >> 8) as u8;
+        self.chunk().code[offset + 1] = jump as u8;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize, span: Span) {
+        self.emit(&[Op::Loop.into()], span);
+
+        // +2 to jump back over the Loop operand too
+        let offset = self.chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error(span, "Loop body too large");
+        }
+
+        self.emit(&[(offset >> 8) as u8, offset as u8], span);
+    }
+
+    fn add_constant(&mut self, value: Value) -> usize {
+        let i = self.chunk().add_constant(value);
+
+        // Constants are only allocated just before being added here,
+        // so this is the first point at which they are all reachable
+        if self.heap.should_collect() {
+            self.heap.collect(&CompilerRoots { compilers: &self.compilers, vm: self.roots });
+        }
+
+        i
+    }
+
+    // Emit an instruction whose operands are `before`, the constant
+    // `constant` and then `after`, in its long form if the constant
+    // doesn't fit in a byte
+    fn emit_with_constant(&mut self, op: Op, before: &[u8], constant: usize, after: &[u8], span: Span) {
+        let mut instruction = vec![];
+        if let Ok(constant) = u8::try_from(constant) {
+            instruction.push(op.into());
+            instruction.extend_from_slice(before);
+            instruction.push(constant);
+        } else if constant < 1 << 24 {
+            instruction.push(op.long().into());
+            instruction.extend_from_slice(before);
+            instruction.extend_from_slice(&[(constant >> 16) as u8, (constant >> 8) as u8, constant as u8]);
+        } else {
+            self.error(span, "Too many constants in one chunk");
+            return;
+        }
+        instruction.extend_from_slice(after);
+        self.emit(&instruction, span);
+    }
+
+    fn load_constant(&mut self, value: Value, dst: u8, span: Span) {
+        let i = self.add_constant(value);
+        self.emit_with_constant(Op::LoadConstant, &[dst], i, &[], span);
+    }
+}
+
+// Whether evaluating `expr` can't assign to any variable. Calls might,
+// through closures capturing the caller's locals
+fn is_pure(expr: &Expr) -> bool {
+    // Walk the tree with a worklist, since long operator chains nest deeply
+    let mut pending = vec![expr];
+    while let Some(expr) = pending.pop() {
+        match expr {
+            Expr::Assign { .. } | Expr::Set { .. } | Expr::Call { .. } => return false,
+            Expr::Number { .. } | Expr::String { .. } | Expr::Bool { .. } | Expr::Nil { .. }
+            | Expr::Variable(_) | Expr::This { .. } | Expr::Super { .. } => (),
+            Expr::Unary { operand, .. } => pending.push(operand),
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                pending.push(left);
+                pending.push(right);
+            },
+            Expr::Grouping { expr, .. } => pending.push(expr),
+            Expr::Get { object, .. } => pending.push(object),
+        }
+    }
+    true
+}
+
+// Whether compiling `expr` into a register writes it only once, after
+// reading everything else. Then it can be compiled straight into the
+// register of a variable it's assigned to, even if it reads that variable
+fn writes_once(expr: &Expr) -> bool {
+    let is_leaf = |expr: &Expr| matches!(expr, Expr::Number { .. } | Expr::String { .. } | Expr::Bool { .. }
+                                              | Expr::Nil { .. } | Expr::Variable(_) | Expr::This { .. });
+    match expr {
+        Expr::Unary { operand, .. } => is_leaf(operand),
+        Expr::Binary { left, right, .. } => is_leaf(left) && is_leaf(right),
+        _ => is_leaf(expr),
+    }
+}
+
+// Compile a program to register code, as `compiler::compile` does to stack code
+pub fn compile(source: &str, source_name: &str, heap: &mut Heap, roots: &dyn Trace
+) -> Result<Function, Vec<Diagnostic>>
+{
+    let source = Rc::new(Source { name: source_name.to_owned(), text: source.to_owned() });
+    let (program, diagnostics) = parser::parse(&source);
+
+    let mut generator = RegisterGenerator::new(Rc::clone(&source), heap, roots);
+    generator.diagnostics = diagnostics;
+    let function = generator.program(&program);
+
+    let mut diagnostics = generator.diagnostics;
+    if diagnostics.is_empty() {
+        Ok(function)
+    } else {
+        diagnostics.sort_by_key(|diagnostic| diagnostic.span.offset);
+        Err(diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::heap::Heap;
+    use crate::register::{compile, instruction_len, Op, OPS};
+    use crate::value::{Function, Value};
+
+    fn compile_script(source: &str, heap: &mut Heap) -> Function {
+        let roots: [Value; 0] = [];
+        compile(source, "test.lox", heap, &roots).unwrap_or_else(|_| panic!("{} should compile", source))
+    }
+
+    #[test]
+    fn ops_decode_to_themselves() {
+        for &op in OPS.iter() {
+            assert_eq!(Op::from(u8::from(op)), op);
+        }
+        assert_eq!(Op::from(OPS.len() as u8), Op::Error);
+        assert_eq!(Op::from(u8::MAX), Op::Error);
+    }
+
+    #[test]
+    fn locals_are_operands_in_place() {
+        let op = |op: Op| -> u8 { op.into() };
+        let script = compile_script("{ var a = 1; var b = 2; a = a + b; print a * -b; }", &mut Heap::default());
+        assert_eq!(script.chunk.code, vec![
+            op(Op::LoadConstant), 1, 0, op(Op::LoadConstant), 2, 1,
+            op(Op::Add), 1, 1, 2,
+            op(Op::Neg), 4, 2, op(Op::Mul), 3, 1, 4, op(Op::Print), 3,
+            op(Op::LoadNil), 1, op(Op::Return), 1,
+        ]);
+        assert_eq!(script.registers, 5);
+    }
+
+    #[test]
+    fn arguments_follow_the_callee() {
+        let op = |op: Op| -> u8 { op.into() };
+        let script = compile_script("fun f(a, b) { return b; } print f(1, 2);", &mut Heap::default());
+        assert_eq!(script.chunk.code, vec![
+            op(Op::Closure), 1, 1, op(Op::DefineGlobal), 1, 0,
+            op(Op::GetGlobal), 1, 0, op(Op::LoadConstant), 2, 2, op(Op::LoadConstant), 3, 3,
+            op(Op::Call), 1, 2, op(Op::Print), 1,
+            op(Op::LoadNil), 1, op(Op::Return), 1,
+        ]);
+    }
+
+    #[test]
+    fn long_chains_compile() {
+        let source = format!("var a = 0; print a{};", " + a".repeat(10_000));
+        let mut heap = Heap::default();
+        let script = compile_script(&source, &mut heap);
+        let (mut offset, mut adds) = (0, 0);
+        while offset < script.chunk.code.len() {
+            if Op::from(script.chunk.code[offset]) == Op::Add {
+                adds += 1;
+            }
+            offset += instruction_len(&script.chunk, offset, &heap);
+        }
+        assert_eq!(adds, 10_000);
+        // The running total stays in one register and each global is loaded
+        // into the same temporary, whatever the length
+        assert_eq!(script.registers, 4);
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt::Write;
+
+use crate::chunk::Source;
+use crate::compiler::Diagnostic;
+use crate::token::Span;
+use crate::vm::{InterpretError, RuntimeError};
+
+// Render an error for people the way rustc does: the message, where it
+// happened, and the source line it's on with the offending span underlined
+pub fn render(error: &InterpretError) -> String {
+    match error {
+        InterpretError::CompileError(diagnostics) => diagnostics.iter().map(render_diagnostic).collect(),
+        InterpretError::RuntimeError(error) => render_runtime_error(error),
+    }
+}
+
+pub fn render_diagnostic(diagnostic: &Diagnostic) -> String {
+    let mut out = String::new();
+    write_error(&mut out, &diagnostic.message, &diagnostic.source, diagnostic.span);
+    out
+}
+
+pub fn render_runtime_error(error: &RuntimeError) -> String {
+    let mut out = String::new();
+    write_error(&mut out, &error.message, &error.source, error.span);
+    let gutter = gutter_width(error.span);
+    for entry in &error.trace {
+        let function = match &entry.function {
+            Some(name) => format!("{}()", name),
+            None => "script".to_owned(),
+        };
+        writeln!(out, "{:w$} = note: in {} at {}:{}:{}", "", function,
+                 entry.source.name, entry.span.line, entry.span.column, w = gutter).unwrap();
+    }
+    out
+}
+
+// The width of the line number column
+fn gutter_width(span: Span) -> usize {
+    span.line.to_string().len()
+}
+
+fn write_error(out: &mut String, message: &str, source: &Source, span: Span) {
+    writeln!(out, "error: {}", message).unwrap();
+    // Code that wasn't compiled from source, like a hand-built chunk, has no location
+    if span.line == 0 {
+        return;
+    }
+
+    let w = gutter_width(span);
+    writeln!(out, "{:w$}--> {}:{}:{}", "", source.name, span.line, span.column, w = w).unwrap();
+    let text = match source.text.get(..span.offset) {
+        Some(before) => &source.text[line_start(before)..],
+        None => return,
+    };
+    let line = text.lines().next().unwrap_or("");
+    let column = span.offset - (source.text.len() - text.len());
+
+    // Indent the carets with the same whitespace as the line, so tabs line up
+    let indent: String = line.get(..column).unwrap_or(line).chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    // Spans that continue onto later lines are underlined to the end of the first.
+    // Empty spans, like the end of the source, still get one caret
+    let end = (column + span.length).min(line.len());
+    let carets = line.get(column..end).map_or(0, |spanned| spanned.chars().count()).max(1);
+
+    writeln!(out, "{:w$} |", "", w = w).unwrap();
+    writeln!(out, "{} | {}", span.line, line).unwrap();
+    writeln!(out, "{:w$} | {}{}", "", indent, "^".repeat(carets), w = w).unwrap();
+}
+
+// The offset of the start of the last line in `before`
+fn line_start(before: &str) -> usize {
+    before.rfind('\n').map_or(0, |newline| newline + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::report::render;
+    use crate::vm::VM;
+
+    fn render_error(source: &str) -> String {
+        let mut vm = VM::with_output(Vec::new());
+        render(&vm.interpret_named(source, "test.lox").unwrap_err())
+    }
+
+    #[test]
+    fn compile_errors_underline_the_token() {
+        assert_eq!(render_error("var x = 1;\nprint x +* 2;"), "\
+error: Expect expression
+ --> test.lox:2:10
+  |
+2 | print x +* 2;
+  |          ^
+");
+        assert_eq!(render_error("print \"unterminated"), "\
+error: Unterminated string
+ --> test.lox:1:7
+  |
+1 | print \"unterminated
+  |       ^^^^^^^^^^^^^
+");
+        // The end of the source still gets a caret
+        assert_eq!(render_error("print 1"), "\
+error: Expect ';' after value
+ --> test.lox:1:8
+  |
+1 | print 1
+  |        ^
+");
+    }
+
+    #[test]
+    fn runtime_errors_underline_the_operator() {
+        assert_eq!(render_error("fun f(a) {\n\treturn a >= \"b\";\n}\nf(1);"), "\
+error: Operands must be numbers
+ --> test.lox:2:11
+  |
+2 | \treturn a >= \"b\";
+  | \t         ^^
+  = note: in f() at test.lox:2:11
+  = note: in script at test.lox:4:4
+");
+        assert_eq!(render_error("var s = \"s\";\nprint -s;"), "\
+error: Operand must be a number
+ --> test.lox:2:7
+  |
+2 | print -s;
+  |       ^
+  = note: in script at test.lox:2:7
+");
+    }
+}
@@ -1,3 +1,6 @@
+use std::borrow::Borrow;
+
+use crate::heap::{Marker, ObjRef, Trace};
 use crate::value::Value;
 
 const MAX_LOAD: f64 = 0.75;
@@ -11,25 +14,50 @@ fn hash(s: &str) -> usize {
     hash
 }
 
-pub struct Table {
-    entries: Vec<Slot>,
+// A type that can be used to look up table entries
+pub trait Key: PartialEq {
+    fn hash(&self) -> usize;
+}
+
+impl Key for str {
+    fn hash(&self) -> usize {
+        hash(self)
+    }
+}
+
+impl Key for String {
+    fn hash(&self) -> usize {
+        hash(self)
+    }
+}
+
+impl Key for ObjRef {
+    // Strings are interned, so a string's handle stands for its contents
+    fn hash(&self) -> usize {
+        self.index().wrapping_mul(2654435761)
+    }
+}
+
+// Tables are keyed by interned strings unless said otherwise
+pub struct Table<K = ObjRef> {
+    entries: Vec<Slot<K>>,
     count: usize,
 }
 
 #[derive(Clone)]
-enum Slot {
+enum Slot<K> {
     Empty,
     Tombstone, // Marks deleted entries
-    Entry(Entry),
+    Entry(Entry<K>),
 }
 
 #[derive(Clone)]
-struct Entry {
-    key: String,
+struct Entry<K> {
+    key: K,
     value: Value,
 }
 
-impl Default for Table {
+impl<K> Default for Table<K> {
     fn default() -> Self {
         Table {
             entries: vec![],
@@ -38,7 +66,7 @@ impl Default for Table {
     }
 }
 
-impl Table {
+impl<K: Key + Clone> Table<K> {
     fn grow_capacity(cap: usize) -> usize {
         if cap < 8 {
             8
@@ -60,20 +88,25 @@ impl Table {
         self.entries = new_entries;
     }
 
-    fn find_entry<'a>(&self, entries: &'a Vec<Slot>, key: &str) -> (usize, &'a Slot) {
-        let mut i = hash(key) % entries.len();
-        let mut last_tombstone: Option<&Slot> = None;
+    fn find_entry<'a, Q>(&self, entries: &'a [Slot<K>], key: &Q) -> (usize, &'a Slot<K>)
+        where K: Borrow<Q>, Q: Key + ?Sized
+    {
+        let mut i = key.hash() % entries.len();
+        // Reuse the first tombstone on the probe sequence, if any
+        let mut first_tombstone: Option<usize> = None;
         loop {
             let slot = &entries[i];
             match slot {
-                Slot::Tombstone => last_tombstone = Some(slot),
-                Slot::Empty => match last_tombstone {
+                Slot::Tombstone => {
+                    first_tombstone.get_or_insert(i);
+                },
+                Slot::Empty => match first_tombstone {
                     None => return (i, &Slot::Empty),
-                    Some(tombstone) => return (i, tombstone),
+                    Some(tombstone) => return (tombstone, &entries[tombstone]),
                 },
                 Slot::Entry(entry) => {
-                    if entry.key == key {
-                        return (i, &slot);
+                    if entry.key.borrow() == key {
+                        return (i, slot);
                     }
                 }
             }
@@ -81,7 +114,7 @@ impl Table {
         }
     }
 
-    pub fn insert(&mut self, key: &str, value: Value) -> Option<Value> {
+    pub fn insert(&mut self, key: K, value: Value) -> Option<Value> {
         if (self.count + 1) as f64 > self.entries.len() as f64 * MAX_LOAD {
             let new_cap = Self::grow_capacity(self.entries.len());
             self.adjust_capacity(new_cap);
@@ -89,7 +122,7 @@ impl Table {
 
         let (i, entry) = self.find_entry(&self.entries, &key);
         let result = match entry {
-            Slot::Entry(entry) => Some(entry.value.clone()),
+            Slot::Entry(entry) => Some(entry.value),
             Slot::Tombstone => None,
             Slot::Empty => {
                 self.count += 1;
@@ -97,13 +130,15 @@ impl Table {
             }
         };
         self.entries[i] = Slot::Entry(Entry {
-            key: key.into(),
+            key,
             value,
         });
         result
     }
 
-    pub fn get(&self, key: &str) -> Option<&Value> {
+    pub fn get<Q>(&self, key: &Q) -> Option<&Value>
+        where K: Borrow<Q>, Q: Key + ?Sized
+    {
         if self.count == 0 {
             return None;
         };
@@ -114,7 +149,34 @@ impl Table {
         }
     }
 
-    fn delete(&mut self, key: &str) -> bool {
+    // Copy every entry of this table into `to`
+    pub fn add_all(&self, to: &mut Table<K>) {
+        for (key, value) in self.iter() {
+            to.insert(key.clone(), *value);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Value)> {
+        self.entries.iter().filter_map(|slot| match slot {
+            Slot::Entry(entry) => Some((&entry.key, &entry.value)),
+            _ => None,
+        })
+    }
+
+    // Delete every entry for which `keep` returns false
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &Value) -> bool) {
+        for slot in self.entries.iter_mut() {
+            if let Slot::Entry(entry) = slot {
+                if !keep(&entry.key, &entry.value) {
+                    *slot = Slot::Tombstone;
+                }
+            }
+        }
+    }
+
+    pub fn delete<Q>(&mut self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Key + ?Sized
+    {
         if self.count == 0 {
             return false;
         }
@@ -131,6 +193,15 @@ impl Table {
     }
 }
 
+impl Trace for Table {
+    fn trace(&self, marker: &mut Marker) {
+        for (key, value) in self.iter() {
+            marker.mark_object(*key);
+            marker.mark_value(*value);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::table::Table;
@@ -140,10 +211,10 @@ mod tests {
     fn lots_of_entries() {
         let mut table = Table::default();
         for i in 0..100 {
-            assert_eq!(table.insert(&i.to_string(), Value::Number(i as f64)), None);
+            assert_eq!(table.insert(i.to_string(), Value::number(i as f64)), None);
         }
         for i in 0..100 {
-            assert_eq!(table.get(&i.to_string()), Some(&Value::Number(i as f64)));
+            assert_eq!(table.get(&i.to_string()), Some(&Value::number(i as f64)));
         }
         for i in -100..0 {
             assert_eq!(table.get(&i.to_string()), None);
@@ -153,17 +224,59 @@ mod tests {
         }
     }
 
+    #[test]
+    fn add_all_entries() {
+        let mut from = Table::default();
+        let mut to = Table::default();
+        for i in 0..20 {
+            from.insert(i.to_string(), Value::number(i as f64));
+        }
+        to.insert("0".to_owned(), Value::NIL);
+        to.insert("extra".to_owned(), Value::bool(true));
+        from.add_all(&mut to);
+        for i in 0..20 {
+            assert_eq!(to.get(&i.to_string()), Some(&Value::number(i as f64)));
+        }
+        assert_eq!(to.get("extra"), Some(&Value::bool(true)));
+    }
+
+    #[test]
+    fn retain_entries() {
+        let mut table = Table::default();
+        for i in 0..50 {
+            table.insert(i.to_string(), Value::number(i as f64));
+        }
+        table.retain(|_, value| *value != Value::number(7.0));
+        assert_eq!(table.get("7"), None);
+        assert_eq!(table.get("8"), Some(&Value::number(8.0)));
+        assert_eq!(table.iter().count(), 49);
+    }
+
+    #[test]
+    fn reinsert_after_delete() {
+        // Reusing tombstones must never fill every slot of the table
+        let mut table = Table::default();
+        for round in 0..50 {
+            for i in 0..5 {
+                table.insert(format!("{}-{}", round, i), Value::NIL);
+            }
+            table.retain(|_, _| false);
+        }
+        assert_eq!(table.get("49-4"), None);
+        assert_eq!(table.iter().count(), 0);
+    }
+
     #[test]
     fn delete_entries() {
         let mut table = Table::default();
         for i in 0..100 {
-            assert_eq!(table.insert(&i.to_string(), Value::Number(i as f64)), None);
+            assert_eq!(table.insert(i.to_string(), Value::number(i as f64)), None);
         }
         for i in (0..100).skip(1).step_by(2) {
-            assert_eq!(table.delete(&i.to_string()), true);
+            assert!(table.delete(&i.to_string()));
         }
         for i in (0..100).step_by(2) {
-            assert_eq!(table.get(&i.to_string()), Some(&Value::Number(i as f64)));
+            assert_eq!(table.get(&i.to_string()), Some(&Value::number(i as f64)));
         }
         for i in (0..100).skip(1).step_by(2) {
             assert_eq!(table.get(&i.to_string()), None);
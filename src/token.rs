@@ -28,4 +28,23 @@ pub struct Token<'a> {
     pub token_type: TokenType,
     pub lexeme: &'a str,
     pub line: usize,        // The source line number of the token
+    pub column: usize,      // The column of the token's first character, counting from 1
+    pub offset: usize,      // The byte offset of the token in the source
+    pub length: usize,      // The token's length in bytes
+}
+
+impl Token<'_> {
+    pub fn span(&self) -> Span {
+        Span { line: self.line, column: self.column, offset: self.offset, length: self.length }
+    }
+}
+
+// A range of source code, located both by byte offset
+// (for slicing the source) and by line and column (for people)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize, // Counting from 1
+    pub offset: usize,
+    pub length: usize, // In bytes
 }
@@ -1,33 +1,190 @@
 use std::fmt;
+use std::rc::Rc;
 
-#[derive(Clone, PartialEq)]
-pub enum Value {
-    Number(f64),
-    Bool(bool),
-    Nil,
-    Obj(Box<Obj>),
+use crate::chunk::Chunk;
+use crate::heap::{Heap, ObjRef};
+use crate::table::Table;
+use crate::vm::Backend;
+
+// A number, bool, nil, or handle to an object in the VM's heap. Strings
+// are interned, so comparing handles compares strings by their contents.
+// Values are built and taken apart only through the accessors each
+// layout provides, so the rest of the interpreter works with either
+#[cfg(not(feature = "nan-boxing"))]
+mod tagged;
+#[cfg(not(feature = "nan-boxing"))]
+pub use tagged::Value;
+
+#[cfg(feature = "nan-boxing")]
+mod nan_boxed;
+#[cfg(feature = "nan-boxing")]
+pub use nan_boxed::Value;
+
+impl Value {
+    // Format the value, looking up any object it refers to in `heap`
+    pub fn display(self, heap: &Heap) -> ValueDisplay<'_> {
+        ValueDisplay { value: self, heap }
+    }
+
+    // Only nil and false are falsey
+    pub fn is_falsey(self) -> bool {
+        self.is_nil() || self.as_bool() == Some(false)
+    }
 }
 
-#[derive(Clone, PartialEq)]
 pub enum Obj {
-    String(String),
+    String(Box<str>), // Interned, see `Heap::intern`
+    // Functions are immutable once compiled, so call frames
+    // share them rather than borrowing them from the heap
+    Function(Rc<Function>),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Native),
+}
+
+pub struct Function {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    // How many registers a call needs, for code compiled for the
+    // register backend. Stack code grows the stack as it goes instead
+    pub registers: usize,
+    pub backend: Backend, // Which instruction set `chunk` is in
+    pub chunk: Chunk,
+    pub name: Option<String>, // None for the top-level script
+}
+
+// A function together with the variables it captured
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<ObjRef>,
+}
+
+// A captured variable
+pub enum Upvalue {
+    // Still lives on the stack, at this index
+    Open(usize),
+    // Moved off the stack when its scope ended
+    Closed(Value),
+}
+
+pub struct Class {
+    pub name: String,
+    pub methods: Table, // Method names to closures
+}
+
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: Table,
+}
+
+// A method closure remembering the instance it was accessed on
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// A function implemented in Rust. It receives the VM's heap and the call's
+// arguments, and returns the call's result or a message to report as a
+// runtime error. It must allocate any objects it returns in the heap
+pub type NativeFn = fn(&mut Heap, &[Value]) -> Result<Value, String>;
+
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+pub struct ValueDisplay<'h> {
+    value: Value,
+    heap: &'h Heap,
 }
 
-impl fmt::Display for Value {
+impl fmt::Display for ValueDisplay<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Bool(b) => write!(f, "{}", b),
-            Value::Nil => write!(f, "nil"),
-            Value::Obj(box obj) => match obj {
-                Obj::String(s) => write!(f, "{}", s),
-            },
+        let obj = match self.value.as_obj() {
+            Some(obj) => obj,
+            None => return write!(f, "{:?}", self.value),
+        };
+        match self.heap.get(obj) {
+            Obj::String(s) => write!(f, "{}", s),
+            Obj::Function(function) => write!(f, "{}", function),
+            Obj::Closure(closure) => write!(f, "{}", closure.function),
+            Obj::Upvalue(_) => write!(f, "upvalue"),
+            Obj::Class(class) => write!(f, "{}", class.name),
+            Obj::Instance(instance) => write!(f, "{} instance", self.heap.class(instance.class).name),
+            Obj::BoundMethod(bound) => write!(f, "{}", self.heap.closure(bound.method).function),
+            Obj::Native(_) => write!(f, "<native fn>"),
         }
     }
 }
 
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(self, f)
+        if let Some(n) = self.as_number() {
+            write!(f, "{}", n)
+        } else if let Some(b) = self.as_bool() {
+            write!(f, "{}", b)
+        } else if let Some(obj) = self.as_obj() {
+            write!(f, "<obj {}>", obj.index())
+        } else {
+            write!(f, "nil")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::heap::Heap;
+    use crate::value::Value;
+
+    #[test]
+    fn accessors_round_trip() {
+        let obj = Heap::default().intern("s");
+        for &n in &[0.0, -0.0, 1.5, -2e300, f64::INFINITY, f64::NEG_INFINITY, f64::MIN_POSITIVE] {
+            assert_eq!(Value::number(n).as_number().map(f64::to_bits), Some(n.to_bits()));
+        }
+        assert!(Value::number(f64::NAN).as_number().unwrap().is_nan());
+        assert_eq!(Value::bool(true).as_bool(), Some(true));
+        assert_eq!(Value::bool(false).as_bool(), Some(false));
+        assert!(Value::obj(obj).as_obj() == Some(obj));
+        assert!(Value::NIL.is_nil());
+
+        // Each accessor only accepts its own kind of value
+        for value in [Value::number(0.0), Value::bool(false), Value::NIL, Value::obj(obj)] {
+            let kinds = [value.as_number().is_some(), value.as_bool().is_some(),
+                         value.is_nil(), value.as_obj().is_some()];
+            assert_eq!(kinds.iter().filter(|&&kind| kind).count(), 1, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn equality() {
+        assert_eq!(Value::number(-0.0), Value::number(0.0));
+        assert_ne!(Value::number(f64::NAN), Value::number(f64::NAN));
+        assert_ne!(Value::number(0.0), Value::bool(false));
+        assert_ne!(Value::NIL, Value::bool(false));
+        assert_eq!(Value::NIL, Value::NIL);
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn nan_boxed_values_are_one_word() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+        // NaNs with payloads that look like boxed values are still numbers
+        let odd_nan = f64::from_bits(0x7fff_ffff_ffff_ffff);
+        assert!(Value::number(odd_nan).as_number().unwrap().is_nan());
+        assert!(Value::number(-f64::NAN).as_obj().is_none());
     }
 }
@@ -0,0 +1,76 @@
+use crate::heap::ObjRef;
+
+// Every double with these bits set is a quiet NaN. Real NaNs are
+// stored without the lowest of them, which leaves the rest of the
+// bits of such NaNs free to hold values that aren't numbers
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+// Set, along with QNAN, for object handles, which go in the low 32 bits
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+// A value packed into the 64 bits of a double: numbers are stored as
+// themselves, and everything else inside the payload of a quiet NaN
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+impl Value {
+    pub const NIL: Value = Value(QNAN | TAG_NIL);
+
+    pub fn number(n: f64) -> Value {
+        // Arithmetic can produce NaNs with any payload, so store
+        // them all as the one NaN that can't be mistaken for a boxed value
+        let n = if n.is_nan() { f64::NAN } else { n };
+        Value(n.to_bits())
+    }
+
+    pub fn bool(b: bool) -> Value {
+        Value(QNAN | if b { TAG_TRUE } else { TAG_FALSE })
+    }
+
+    pub fn obj(obj: ObjRef) -> Value {
+        Value(SIGN_BIT | QNAN | obj.raw() as u64)
+    }
+
+    pub fn is_nil(self) -> bool {
+        self.0 == Value::NIL.0
+    }
+
+    pub fn as_number(self) -> Option<f64> {
+        if self.0 & QNAN != QNAN {
+            Some(f64::from_bits(self.0))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_bool(self) -> Option<bool> {
+        if self.0 == QNAN | TAG_TRUE {
+            Some(true)
+        } else if self.0 == QNAN | TAG_FALSE {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_obj(self) -> Option<ObjRef> {
+        if self.0 & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN {
+            Some(ObjRef::from_raw(self.0 as u32))
+        } else {
+            None
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        // Numbers compare as doubles, so that NaN != NaN and -0 == 0
+        match (self.as_number(), other.as_number()) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.0 == other.0,
+        }
+    }
+}
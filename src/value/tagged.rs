@@ -0,0 +1,54 @@
+use crate::heap::ObjRef;
+
+// A value as a plain Rust enum. Simple and safe, but twice the size of
+// a NaN-boxed value. Its variants stay public so code built against
+// them keeps working, but the interpreter itself only uses the
+// accessors, which are all `nan_boxed` has
+#[derive(Clone, Copy, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Bool(bool),
+    Nil,
+    Obj(ObjRef),
+}
+
+impl Value {
+    pub const NIL: Value = Value::Nil;
+
+    pub fn number(n: f64) -> Value {
+        Value::Number(n)
+    }
+
+    pub fn bool(b: bool) -> Value {
+        Value::Bool(b)
+    }
+
+    pub fn obj(obj: ObjRef) -> Value {
+        Value::Obj(obj)
+    }
+
+    pub fn is_nil(self) -> bool {
+        self == Value::Nil
+    }
+
+    pub fn as_number(self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_obj(self) -> Option<ObjRef> {
+        match self {
+            Value::Obj(obj) => Some(obj),
+            _ => None,
+        }
+    }
+}
@@ -1,193 +1,1976 @@
-use crate::chunk::{Chunk, Opcode};
-use crate::compiler::compile;
-use crate::value::{Value, Obj};
+use std::io::{self, Write};
+use std::mem;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, Opcode, Source};
+use crate::compiler::{compile, Diagnostic};
+use crate::heap::{Heap, Marker, ObjRef, Trace};
+use crate::natives;
+use crate::register::{self, Op};
+use crate::table::Table;
+use crate::token::Span;
+use crate::value::{BoundMethod, Class, Closure, Function, Instance, Native, NativeFn, Upvalue, Value, Obj};
 
 pub const DEBUG: bool = false;
-// TODO: contrain VM::stack somehow?
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
+// The default size of the value stack: enough for every frame to fill all its local slots
+pub const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+
+// The instruction set programs are compiled to and run as
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Stack,    // Operands are pushed to and popped from the value stack
+    Register, // Instructions address the slots of a call's window on the stack directly
+}
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum InterpretError {
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
+}
+
+// What went wrong when a program stopped with a runtime error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuntimeErrorKind {
+    StackOverflow,     // The value stack is full
+    CallDepthExceeded, // Too many calls are in progress at once
+    StackUnderflow,    // Malformed bytecode read past the bottom of the stack
+    UnknownOpcode,
+    WrongBackend,      // A call to a function compiled for the backend not in use
+    Type,              // A value can't be used the way an instruction uses it
+    Arity,             // A call passed the wrong number of arguments
+    UndefinedVariable,
+    UndefinedProperty,
+    Output,            // Printing failed
+    Native,            // A native function reported an error
+}
+
+// An error that stopped a program while it was running
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub message: String,
+    pub source: Rc<Source>, // The source of the code that failed
+    pub span: Span,
+    pub trace: Vec<TraceEntry>, // The calls that led to the error, innermost first
+}
+
+// A call that was in progress when a runtime error happened
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    pub function: Option<String>, // None for top-level code
+    pub source: Rc<Source>,
+    pub span: Span,
+}
+
+// An error raised while running an instruction. The run loop locates
+// it in the code and reports it as a `RuntimeError`
+struct Fault {
+    kind: RuntimeErrorKind,
+    message: String,
+}
+
+impl Fault {
+    fn new(kind: RuntimeErrorKind, message: impl Into<String>) -> Fault {
+        Fault { kind, message: message.into() }
+    }
+
+    // Only malformed bytecode pops more values than it pushed
+    fn stack_underflow() -> Fault {
+        Fault::new(RuntimeErrorKind::StackUnderflow, "Stack underflow")
+    }
+
+    fn arity(arity: usize, arg_count: usize) -> Fault {
+        Fault::new(RuntimeErrorKind::Arity, format!("Expected {} arguments but got {}", arity, arg_count))
+    }
+}
+
+// An ongoing function call
+struct CallFrame {
+    closure: ObjRef,
+    function: Rc<Function>, // The closure's function, so it needn't be looked up
+    ip: usize,    // The offset of the current instruction in the function's chunk
+    slots: usize, // The stack index of the frame's slot 0
+}
+
+impl Trace for CallFrame {
+    fn trace(&self, marker: &mut Marker) {
+        marker.mark_object(self.closure);
+    }
+}
+
+// Everything the VM can reach objects from
+struct Roots<'a> {
+    stack: &'a [Value],
+    frames: &'a [CallFrame],
+    open_upvalues: &'a [ObjRef],
+    globals: &'a Table,
+    init_string: ObjRef,
+}
+
+impl Trace for Roots<'_> {
+    fn trace(&self, marker: &mut Marker) {
+        self.stack.trace(marker);
+        self.frames.trace(marker);
+        self.open_upvalues.trace(marker);
+        self.globals.trace(marker);
+        marker.mark_object(self.init_string);
+    }
 }
 
 pub struct VM {
-    ip: usize,
-    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    stack: Vec<Value>, // Never holds more than `stack_size` values
+    stack_size: usize,
+    // Upvalues still pointing into the stack, ordered by stack index
+    open_upvalues: Vec<ObjRef>,
+    globals: Table,      // Outlives a single `interpret` so REPL state persists
+    heap: Heap,
+    init_string: ObjRef, // The name initializers are looked up by
+    optimize: bool,      // Whether to optimize code after compiling it
+    backend: Backend,
+    out: Box<dyn Write>, // Where `print` statements write to
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
-        VM {
-            ip: 0,
-            stack: Vec::new(),
+        VM::with_output(io::stdout())
+    }
+
+    pub fn with_output(out: impl Write + 'static) -> VM {
+        let mut heap = Heap::default();
+        let init_string = heap.intern("init");
+        let mut vm = VM {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: Vec::with_capacity(STACK_MAX),
+            stack_size: STACK_MAX,
+            open_upvalues: vec![],
+            globals: Table::default(),
+            heap,
+            init_string,
+            optimize: true,
+            backend: Backend::Stack,
+            out: Box::new(out),
+        };
+        for &(name, arity, function) in natives::STANDARD.iter() {
+            vm.define_native(name, arity, function);
         }
+        vm
+    }
+
+    // Make a Rust function callable from Lox as the global `name`
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = Native { name: name.to_owned(), arity, function };
+        let name = self.heap.intern(name);
+        let native = self.heap.alloc(Obj::Native(native));
+        self.globals.insert(name, Value::obj(native));
+    }
+
+    // Limit how many values the stack can hold, so runaway programs
+    // fail with a stack overflow instead of exhausting memory
+    pub fn set_stack_size(&mut self, size: usize) {
+        self.stack_size = size;
+        // Reserve it all up front, so pushing never reallocates
+        self.stack = Vec::with_capacity(size);
+    }
+
+    // Run the peephole optimizer over compiled code. On by default.
+    // Register code isn't optimized, so this only affects the stack backend
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    // Compile and run programs with `backend`. Functions compiled for one
+    // backend can't run on the other, so calling a function defined before
+    // switching is a runtime error
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    // Collect garbage whenever the VM gets the chance, to test that
+    // every object in use is reachable from the VM's roots
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    // Called after instructions that allocate, once
+    // everything they allocated is reachable
+    fn maybe_collect(&mut self) {
+        if !self.heap.should_collect() {
+            return;
+        }
+        let roots = Roots {
+            stack: &self.stack,
+            frames: &self.frames,
+            open_upvalues: &self.open_upvalues,
+            globals: &self.globals,
+            init_string: self.init_string,
+        };
+        self.heap.collect(&roots);
     }
 
     fn reset(&mut self) {
-        self.ip = 0;
-        self.stack = Vec::new();
+        self.frames.clear();
+        self.stack.clear();
+        self.open_upvalues.clear();
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
     }
 
-    fn push(&mut self, value: Value) {
-        self.stack.push(value)
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
     }
 
-    fn pop(&mut self) -> Result<Value, InterpretError> {
-        match self.stack.pop() {
-            Some(val) => Ok(val),
-            None => Err(InterpretError::RuntimeError), // TODO: StackEmpty
+    fn push(&mut self, value: Value) -> Result<(), Fault> {
+        if self.stack.len() == self.stack_size {
+            return Err(Fault::new(RuntimeErrorKind::StackOverflow, "Stack overflow"));
         }
+        self.stack.push(value);
+        Ok(())
     }
 
-    fn runtime_error(&mut self, chunk: &Chunk, message: &str) {
-        eprintln!("{}", message);
-        let line = chunk.line_at(self.ip);
-        eprintln!("[line {}] in script", line);
+    fn pop(&mut self) -> Result<Value, Fault> {
+        self.stack.pop().ok_or_else(Fault::stack_underflow)
+    }
 
-        self.reset();
+    // The index of the value `distance` below the top of the stack
+    fn slot(&self, distance: usize) -> Result<usize, Fault> {
+        self.stack.len().checked_sub(distance + 1).ok_or_else(Fault::stack_underflow)
     }
 
-    fn is_falsey(&self, value: Value) -> bool {
-        value == Value::Nil || value == Value::Bool(false)
+    fn peek(&self, distance: usize) -> Result<Value, Fault> {
+        Ok(self.stack[self.slot(distance)?])
     }
 
-    fn unary_op(&mut self, chunk: &Chunk, op: impl Fn(f64) -> f64
-    ) -> Result<usize, InterpretError>
-    {
-        match self.pop()? {
-            Value::Number(lhs) => {
-                self.push(Value::Number(op(lhs)));
-                Ok(self.ip + 1)
-            },
-            _ => {
-                self.runtime_error(chunk, "Operand must be a number");
-                Err(InterpretError::RuntimeError)
-            }
+    // The two operands of a binary instruction, left to right. They're only
+    // looked at, so nothing is popped until the instruction knows it can run
+    fn operands(&self) -> Result<(Value, Value), Fault> {
+        match self.stack[..] {
+            [.., lhs, rhs] => Ok((lhs, rhs)),
+            _ => Err(Fault::stack_underflow()),
         }
     }
 
-    fn binary_op(&mut self, chunk: &Chunk, op: impl Fn(f64, f64) -> f64
-    ) -> Result<usize, InterpretError>
-    {
-        // TODO: any way to avoid popping until we know they're Numbers?
-        match (self.pop()?, self.pop()?) {
-            (Value::Number(rhs), Value::Number(lhs)) => {
-                self.push(Value::Number(op(lhs, rhs)));
-                Ok(self.ip + 1)
-            },
-            _ => {
-                self.runtime_error(chunk, "Operands must be numbers");
-                Err(InterpretError::RuntimeError)
-            }
+    // Replace the two operands of a binary instruction with its result
+    fn replace_operands(&mut self, result: Value) {
+        self.stack.pop();
+        if let Some(lhs) = self.stack.last_mut() {
+            *lhs = result;
         }
     }
 
-    fn add(&mut self, chunk: &Chunk) -> Result<usize, InterpretError> {
-        match (self.pop()?, self.pop()?) {
-            (Value::Number(rhs), Value::Number(lhs)) => {
-                self.push(Value::Number(lhs + rhs));
-                Ok(self.ip + 1)
-            },
-            // TODO: will have to distinguish between Obj::String and other
-            // heap-allocated objects when they exist
-            (Value::Obj(box rhs), Value::Obj(box lhs)) => {
-                let Obj::String(str_lhs) = lhs;
-                let Obj::String(str_rhs) = rhs;
-                // TODO: should i clone str_lhs?
-                let concat = Box::new(Obj::String(str_lhs + &str_rhs));
-                self.push(Value::Obj(concat));
-                Ok(self.ip + 1)
+    // The operand of a unary instruction, to be replaced with its result
+    fn operand(&mut self) -> Result<&mut Value, Fault> {
+        self.stack.last_mut().ok_or_else(Fault::stack_underflow)
+    }
+
+    // Read the name operand of a global variable or property instruction,
+    // along with the offset just past it
+    fn read_name(chunk: &Chunk, offset: usize, long: bool) -> (ObjRef, usize) {
+        let (addr, next) = chunk.read_constant(offset, long);
+        (chunk.constants[addr].as_obj().expect("Name constant must be a string"), next)
+    }
+
+    fn as_class(&self, value: Value) -> Option<ObjRef> {
+        value.as_obj().filter(|&obj| matches!(self.heap.get(obj), Obj::Class(_)))
+    }
+
+    fn as_instance(&self, value: Value) -> Option<ObjRef> {
+        value.as_obj().filter(|&obj| matches!(self.heap.get(obj), Obj::Instance(_)))
+    }
+
+    fn as_string(&self, value: Value) -> Option<ObjRef> {
+        value.as_obj().filter(|&obj| matches!(self.heap.get(obj), Obj::String(_)))
+    }
+
+    fn undefined_variable(&self, name: ObjRef) -> Fault {
+        Fault::new(RuntimeErrorKind::UndefinedVariable, format!("Undefined variable '{}'", self.heap.string(name)))
+    }
+
+    // Build the error to report for `fault`, and reset the VM
+    fn runtime_error(&mut self, fault: Fault) -> InterpretError {
+        // Trace the calls that led here, innermost first
+        let trace: Vec<TraceEntry> = self.frames.iter().enumerate().rev().map(|(i, frame)| {
+            // Callers have already moved past their call instruction
+            let offset = if i == self.frames.len() - 1 { frame.ip } else { frame.ip - 1 };
+            let chunk = &frame.function.chunk;
+            TraceEntry { function: frame.function.name.clone(), source: Rc::clone(&chunk.source), span: chunk.span_at(offset) }
+        }).collect();
+        let (source, span) = trace.first()
+            .map_or_else(|| (Rc::default(), Span::default()), |entry| (Rc::clone(&entry.source), entry.span));
+
+        self.reset();
+        InterpretError::RuntimeError(RuntimeError {
+            kind: fault.kind,
+            message: fault.message,
+            source,
+            span,
+            trace,
+        })
+    }
+
+    // The slow path of `+`, for anything but two numbers. The
+    // caller collects garbage once the result is reachable
+    fn concatenate(&mut self, lhs: Value, rhs: Value) -> Result<Value, Fault> {
+        match (self.as_string(lhs), self.as_string(rhs)) {
+            (Some(lhs), Some(rhs)) => {
+                let concat = self.heap.string(lhs).to_owned() + self.heap.string(rhs);
+                Ok(Value::obj(self.heap.intern(&concat)))
             },
-            _ => {
-                self.runtime_error(chunk, "Operands must be two numbers or two strings");
-                Err(InterpretError::RuntimeError)
-            }
+            _ => Err(Fault::new(RuntimeErrorKind::Type, "Operands must be two numbers or two strings")),
+        }
+    }
+
+    // Calls find their callee at stack index `base`, with the arguments
+    // right after it: on top of the stack for stack code, and in the
+    // registers the instruction names for register code
+
+    // Push a frame for a call to `closure`
+    fn call(&mut self, closure: ObjRef, base: usize, arg_count: usize) -> Result<(), Fault> {
+        let function = Rc::clone(&self.heap.closure(closure).function);
+        let arity = function.arity;
+        if arg_count != arity {
+            return Err(Fault::arity(arity, arg_count));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(Fault::new(RuntimeErrorKind::CallDepthExceeded, "Too many nested calls"));
+        }
+        if function.backend != self.backend {
+            return Err(Fault::new(RuntimeErrorKind::WrongBackend, "Can't call code compiled for the other backend"));
+        }
+
+        if self.backend == Backend::Register {
+            self.resize_window(base + function.registers)?;
+        }
+        self.frames.push(CallFrame { closure, function, ip: 0, slots: base });
+        Ok(())
+    }
+
+    // Grow or shrink the stack to end at `top`, the end of the innermost
+    // register window. Registers come into use holding nil
+    fn resize_window(&mut self, top: usize) -> Result<(), Fault> {
+        if top > self.stack_size {
+            return Err(Fault::new(RuntimeErrorKind::StackOverflow, "Stack overflow"));
         }
+        self.stack.resize(top, Value::NIL);
+        Ok(())
     }
 
-    fn eq(&mut self, _: &Chunk) -> Result<usize, InterpretError> {
-        match (self.pop()?, self.pop()?) {
-            (Value::Obj(box rhs), Value::Obj(box lhs)) => {
-                // TODO: might need to be different when there are other
-                // heap-allocated objects besides strings
-                self.push(Value::Bool(rhs == lhs))
+    fn call_value(&mut self, base: usize, arg_count: usize) -> Result<(), Fault> {
+        let callee = match self.stack[base].as_obj() {
+            Some(callee) => callee,
+            None => {
+                return Err(Fault::new(RuntimeErrorKind::Type, "Can only call functions and classes"));
             },
-            (Value::Number(rhs), Value::Number(lhs)) => {
-                self.push(Value::Bool(rhs == lhs))
+        };
+
+        match self.heap.get(callee) {
+            Obj::Closure(_) => self.call(callee, base, arg_count),
+            Obj::Class(class) => {
+                let initializer = class.methods.get(&self.init_string).and_then(|init| init.as_obj());
+
+                // The new instance replaces the class in the callee slot,
+                // where the initializer will find it as `this`
+                let instance = self.heap.alloc(Obj::Instance(Instance { class: callee, fields: Table::default() }));
+                self.stack[base] = Value::obj(instance);
+                self.maybe_collect();
+
+                match initializer {
+                    Some(init) => self.call(init, base, arg_count),
+                    _ if arg_count != 0 => {
+                        Err(Fault::arity(0, arg_count))
+                    },
+                    _ => Ok(()),
+                }
             },
-            (Value::Bool(rhs), Value::Bool(lhs)) => {
-                self.push(Value::Bool(rhs == lhs))
+            Obj::Native(native) => {
+                let (arity, function) = (native.arity, native.function);
+                if arg_count != arity {
+                    return Err(Fault::arity(arity, arg_count));
+                }
+
+                let args = base + 1..base + 1 + arg_count;
+                match function(&mut self.heap, &self.stack[args]) {
+                    Ok(result) => {
+                        // The result takes the callee's place. On the stack,
+                        // the arguments are popped too
+                        self.stack[base] = result;
+                        if self.backend == Backend::Stack {
+                            self.stack.truncate(base + 1);
+                        }
+                        self.maybe_collect();
+                        Ok(())
+                    },
+                    Err(message) => {
+                        Err(Fault::new(RuntimeErrorKind::Native, message))
+                    },
+                }
             },
-            (Value::Nil, Value::Nil) => {
-                self.push(Value::Bool(true))
+            Obj::BoundMethod(bound) => {
+                let (receiver, method) = (bound.receiver, bound.method);
+                // The receiver takes the callee's slot, where the method finds it as `this`
+                self.stack[base] = receiver;
+                self.call(method, base, arg_count)
             },
             _ => {
-                self.push(Value::Bool(false))
+                Err(Fault::new(RuntimeErrorKind::Type, "Can only call functions and classes"))
             },
-        };
-        Ok(self.ip + 1)
+        }
     }
 
-    fn cmp(&mut self, chunk: &Chunk, op: impl Fn(&f64, &f64) -> bool
-    ) -> Result<usize, InterpretError>
-    {
-        match (self.pop()?, self.pop()?) {
-            (Value::Number(rhs), Value::Number(lhs)) => {
-                self.push(Value::Bool(op(&lhs, &rhs)));
-                return Ok(self.ip + 1)
+    // Look up the method `name` of `class`, reporting an error if there is none
+    fn find_method(&mut self, class: ObjRef, name: ObjRef) -> Result<ObjRef, Fault> {
+        match self.heap.class(class).methods.get(&name).and_then(|method| method.as_obj()) {
+            Some(method) => Ok(method),
+            None => {
+                let message = format!("Undefined property '{}'", self.heap.string(name));
+                Err(Fault::new(RuntimeErrorKind::UndefinedProperty, message))
             },
-            _ => {
-                self.runtime_error(chunk, "Operands must be numbers");
-                Err(InterpretError::RuntimeError)
+        }
+    }
+
+    fn invoke_from_class(&mut self, class: ObjRef, name: ObjRef, base: usize, arg_count: usize) -> Result<(), Fault> {
+        let method = self.find_method(class, name)?;
+        self.call(method, base, arg_count)
+    }
+
+    // Call the method `name` on the receiver in the callee's slot
+    fn invoke(&mut self, name: ObjRef, base: usize, arg_count: usize) -> Result<(), Fault> {
+        let instance = match self.as_instance(self.stack[base]) {
+            Some(instance) => instance,
+            None => {
+                return Err(Fault::new(RuntimeErrorKind::Type, "Only instances have methods"));
+            },
+        };
+
+        // A field holding a function shadows a method of the same name
+        let instance = self.heap.instance(instance);
+        if let Some(&field) = instance.fields.get(&name) {
+            self.stack[base] = field;
+            return self.call_value(base, arg_count);
+        }
+
+        self.invoke_from_class(instance.class, name, base, arg_count)
+    }
+
+    // Bind the method `name` of `class` to `receiver`. The caller
+    // collects garbage once the bound method is reachable
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef, receiver: Value) -> Result<Value, Fault> {
+        let method = self.find_method(class, name)?;
+        Ok(Value::obj(self.heap.alloc(Obj::BoundMethod(BoundMethod { receiver, method }))))
+    }
+
+    // The stack index an open upvalue points to
+    fn open_slot(&self, upvalue: ObjRef) -> usize {
+        match self.heap.upvalue(upvalue) {
+            Upvalue::Open(slot) => *slot,
+            Upvalue::Closed(_) => unreachable!("Closed upvalue in open list"),
+        }
+    }
+
+    // Find or create the upvalue for the local at stack index `slot`
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let position = self.open_upvalues.iter().rposition(|&upvalue| self.open_slot(upvalue) <= slot);
+        if let Some(i) = position {
+            let upvalue = self.open_upvalues[i];
+            if self.open_slot(upvalue) == slot {
+                // Closures capturing the same variable share its upvalue
+                return upvalue;
+            }
+        }
+
+        let upvalue = self.heap.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        let insert_at = position.map_or(0, |i| i + 1);
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    // Move every open upvalue at or above stack index `last` off the stack
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let slot = self.open_slot(upvalue);
+            if slot < last {
+                break;
             }
+            *self.heap.upvalue_mut(upvalue) = Upvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
         }
     }
 
     pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
+        self.interpret_named(source, "<script>")
+    }
+
+    // Run `source`, naming it `source_name` in any errors
+    pub fn interpret_named(&mut self, source: &str, source_name: &str) -> Result<(), InterpretError> {
+        self.reset();
+
+        // Only globals survive between calls, but the compiler
+        // may collect garbage, so it's handed all the roots
+        let roots = Roots {
+            stack: &self.stack,
+            frames: &self.frames,
+            open_upvalues: &self.open_upvalues,
+            globals: &self.globals,
+            init_string: self.init_string,
+        };
+        let function = match self.backend {
+            Backend::Stack => compile(source, source_name, &mut self.heap, &roots, self.optimize),
+            Backend::Register => register::compile(source, source_name, &mut self.heap, &roots),
+        };
+        let function = function.map_err(InterpretError::CompileError)?;
+
+        self.interpret_function(function)
+    }
+
+    // Run a chunk of top-level stack code. It's an error on the register backend
+    pub fn interpret_chunk(&mut self, chunk: Chunk) -> Result<(), InterpretError> {
         self.reset();
+        self.interpret_function(Function { arity: 0, upvalue_count: 0, registers: 0, backend: Backend::Stack, chunk,
+                                           name: None })
+    }
 
-        let chunk = compile(source)?;
+    fn interpret_function(&mut self, function: Function) -> Result<(), InterpretError> {
+        let closure = self.heap.alloc(Obj::Closure(Closure { function: Rc::new(function), upvalues: vec![] }));
+        let called = self.push(Value::obj(closure)).and_then(|_| self.call(closure, 0, 0));
+        if let Err(fault) = called {
+            return Err(self.runtime_error(fault));
+        }
+        self.maybe_collect();
 
-        self.interpret_chunk(&chunk)
+        self.run()
     }
 
-    pub fn interpret_chunk(&mut self, chunk: &Chunk) -> Result<(), InterpretError> {
+    fn run(&mut self) -> Result<(), InterpretError> {
+        loop {
+            // Run the innermost frame until it returns or calls. Its code
+            // and position are kept in locals meanwhile, and the position
+            // is only written back to the frame when another one takes over
+            let frame = self.frame();
+            let (function, closure, slots) = (Rc::clone(&frame.function), frame.closure, frame.slots);
+            let mut ip = frame.ip;
+            let next = match self.backend {
+                Backend::Stack => self.run_frame(&function.chunk, closure, slots, &mut ip),
+                Backend::Register => self.run_registers(&function.chunk, closure, slots, &mut ip),
+            };
+            match next {
+                Ok(Next::Frame) => (),
+                Ok(Next::Done) => return Ok(()),
+                Err(fault) => {
+                    // Errors point at the instruction that raised them
+                    self.frame_mut().ip = ip;
+                    return Err(self.runtime_error(fault));
+                },
+            }
+        }
+    }
+
+    // Run the code of the innermost frame, whose closure is `closure` and
+    // whose locals start at stack index `slots`, from offset `ip` on.
+    // Before switching frames, the frame's return address is saved in it
+    fn run_frame(&mut self, chunk: &Chunk, closure: ObjRef, slots: usize, ip: &mut usize) -> Result<Next, Fault> {
+        let code = &chunk.code[..];
+        let constants = &chunk.constants[..];
+
+        // Apply an arithmetic operator to the two numbers on top of the stack,
+        // leaving the result in their place. Only numbers are popped
+        macro_rules! binary_op {
+            ($result:path, $op:tt) => {{
+                let (lhs, rhs) = self.operands()?;
+                match (lhs.as_number(), rhs.as_number()) {
+                    (Some(lhs), Some(rhs)) => self.replace_operands($result(lhs $op rhs)),
+                    _ => return Err(Fault::new(RuntimeErrorKind::Type, "Operands must be numbers")),
+                }
+                *ip + 1
+            }};
+        }
+
         loop {
             if DEBUG {
                 // Print stack
-                println!("\t{:?}", self.stack);
-                chunk.disassemble_instruction(self.ip);
+                print!("\t");
+                for value in &self.stack {
+                    print!("[ {} ]", value.display(&self.heap));
+                }
+                println!();
+                chunk.disassemble_instruction(*ip, &self.heap);
             }
-            self.ip = match Opcode::from(chunk.code[self.ip]) {
+            let opcode = Opcode::from(code[*ip]);
+            *ip = match opcode {
                 Opcode::Return => {
-                    println!("{}", self.pop()?);
-                    return Ok(())
+                    let result = self.pop()?;
+                    let frame = self.frames.pop().unwrap();
+                    // Discard the callee and its locals
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    if self.frames.is_empty() {
+                        return Ok(Next::Done);
+                    }
+                    self.push(result)?;
+                    return Ok(Next::Frame);
+                },
+                Opcode::Call => {
+                    let arg_count = code[*ip + 1] as usize;
+                    // Save the return address before switching frames
+                    self.frame_mut().ip = *ip + 2;
+                    self.call_value(self.slot(arg_count)?, arg_count)?;
+                    return Ok(Next::Frame);
+                },
+                Opcode::Closure | Opcode::ClosureLong => {
+                    let (addr, next) = chunk.read_constant(*ip + 1, opcode.is_long());
+                    let function = constants[addr].as_obj().expect("Closure constant must be a function");
+                    let function = Rc::clone(self.heap.function(function));
+
+                    let mut offset = next;
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = code[offset] == 1;
+                        let index = code[offset + 1] as usize;
+                        upvalues.push(if is_local {
+                            self.capture_upvalue(slots + index)
+                        } else {
+                            self.heap.closure(closure).upvalues[index]
+                        });
+                        offset += 2;
+                    }
+
+                    let closure = self.heap.alloc(Obj::Closure(Closure { function, upvalues }));
+                    self.push(Value::obj(closure))?;
+                    self.maybe_collect();
+                    offset
+                },
+                Opcode::GetUpvalue => {
+                    let index = code[*ip + 1] as usize;
+                    let upvalue = self.heap.closure(closure).upvalues[index];
+                    let val = match self.heap.upvalue(upvalue) {
+                        Upvalue::Open(slot) => self.stack[*slot],
+                        Upvalue::Closed(val) => *val,
+                    };
+                    self.push(val)?;
+                    *ip + 2
+                },
+                Opcode::SetUpvalue => {
+                    let index = code[*ip + 1] as usize;
+                    let val = self.peek(0)?;
+                    let upvalue = self.heap.closure(closure).upvalues[index];
+                    match self.heap.upvalue_mut(upvalue) {
+                        Upvalue::Open(slot) => self.stack[*slot] = val,
+                        Upvalue::Closed(closed) => *closed = val,
+                    }
+                    *ip + 2
+                },
+                Opcode::CloseUpvalue => {
+                    // The local to close is on top of the stack
+                    self.close_upvalues(self.slot(0)?);
+                    self.pop()?;
+                    *ip + 1
+                },
+                Opcode::Class | Opcode::ClassLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 1, opcode.is_long());
+                    let name = self.heap.string(name).to_owned();
+                    let class = self.heap.alloc(Obj::Class(Class { name, methods: Table::default() }));
+                    self.push(Value::obj(class))?;
+                    self.maybe_collect();
+                    next
+                },
+                Opcode::GetProperty | Opcode::GetPropertyLong => {
+                    let instance = match self.as_instance(self.peek(0)?) {
+                        Some(instance) => self.heap.instance(instance),
+                        None => return Err(Fault::new(RuntimeErrorKind::Type, "Only instances have properties")),
+                    };
+                    let (name, next) = Self::read_name(chunk, *ip + 1, opcode.is_long());
+                    // Replace the instance with the field's value, or else the bound method
+                    match instance.fields.get(&name) {
+                        Some(&val) => *self.operand()? = val,
+                        None => {
+                            let bound = self.bind_method(instance.class, name, self.peek(0)?)?;
+                            *self.operand()? = bound;
+                            self.maybe_collect();
+                        },
+                    }
+                    next
+                },
+                Opcode::SetProperty | Opcode::SetPropertyLong => {
+                    let instance = match self.as_instance(self.peek(1)?) {
+                        Some(instance) => instance,
+                        None => return Err(Fault::new(RuntimeErrorKind::Type, "Only instances have fields")),
+                    };
+                    let (name, next) = Self::read_name(chunk, *ip + 1, opcode.is_long());
+                    // Leave the assigned value as the result, in place of the instance
+                    let (_, val) = self.operands()?;
+                    self.heap.instance_mut(instance).fields.insert(name, val);
+                    self.replace_operands(val);
+                    next
+                },
+                Opcode::Method | Opcode::MethodLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 1, opcode.is_long());
+                    let method = self.pop()?;
+                    let class = self.as_class(self.peek(0)?).expect("Methods are only defined on classes");
+                    self.heap.class_mut(class).methods.insert(name, method);
+                    next
+                },
+                Opcode::Invoke | Opcode::InvokeLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 1, opcode.is_long());
+                    let arg_count = code[next] as usize;
+                    self.frame_mut().ip = next + 1;
+                    self.invoke(name, self.slot(arg_count)?, arg_count)?;
+                    return Ok(Next::Frame);
+                },
+                Opcode::Inherit => {
+                    let superclass = match self.as_class(self.peek(1)?) {
+                        Some(superclass) => superclass,
+                        None => return Err(Fault::new(RuntimeErrorKind::Type, "Superclass must be a class")),
+                    };
+                    let subclass = self.as_class(self.peek(0)?).expect("Only classes inherit");
+                    // Copy-down inheritance: methods the subclass
+                    // defines later override the copied ones
+                    let mut methods = mem::take(&mut self.heap.class_mut(subclass).methods);
+                    self.heap.class(superclass).methods.add_all(&mut methods);
+                    self.heap.class_mut(subclass).methods = methods;
+                    self.pop()?;
+                    *ip + 1
+                },
+                Opcode::GetSuper | Opcode::GetSuperLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 1, opcode.is_long());
+                    let superclass = self.pop()?;
+                    let superclass = self.as_class(superclass).expect("'super' must be a class");
+                    let bound = self.bind_method(superclass, name, self.peek(0)?)?;
+                    *self.operand()? = bound;
+                    self.maybe_collect();
+                    next
+                },
+                Opcode::SuperInvoke | Opcode::SuperInvokeLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 1, opcode.is_long());
+                    let arg_count = code[next] as usize;
+                    let superclass = self.pop()?;
+                    let superclass = self.as_class(superclass).expect("'super' must be a class");
+                    self.frame_mut().ip = next + 1;
+                    self.invoke_from_class(superclass, name, self.slot(arg_count)?, arg_count)?;
+                    return Ok(Next::Frame);
+                },
+                Opcode::Print => {
+                    let val = self.pop()?;
+                    if writeln!(self.out, "{}", val.display(&self.heap)).is_err() {
+                        return Err(Fault::new(RuntimeErrorKind::Output, "Could not write output"));
+                    }
+                    *ip + 1
+                },
+                Opcode::Pop => { self.pop()?; *ip + 1 },
+                Opcode::GetLocal => {
+                    let slot = code[*ip + 1] as usize;
+                    self.push(self.stack[slots + slot])?;
+                    *ip + 2
+                },
+                Opcode::SetLocal => {
+                    let slot = code[*ip + 1] as usize;
+                    self.stack[slots + slot] = self.peek(0)?;
+                    *ip + 2
+                },
+                Opcode::Jump => {
+                    let jump = chunk.read_u16(*ip + 1) as usize;
+                    *ip + 3 + jump
+                },
+                Opcode::JumpIfFalse => {
+                    let jump = chunk.read_u16(*ip + 1) as usize;
+                    if self.peek(0)?.is_falsey() {
+                        *ip + 3 + jump
+                    } else {
+                        *ip + 3
+                    }
+                },
+                Opcode::Loop => {
+                    let jump = chunk.read_u16(*ip + 1) as usize;
+                    *ip + 3 - jump
+                },
+                Opcode::DefineGlobal | Opcode::DefineGlobalLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 1, opcode.is_long());
+                    let val = self.pop()?;
+                    self.globals.insert(name, val);
+                    next
+                },
+                Opcode::GetGlobal | Opcode::GetGlobalLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 1, opcode.is_long());
+                    match self.globals.get(&name) {
+                        Some(&val) => self.push(val)?,
+                        None => return Err(self.undefined_variable(name)),
+                    }
+                    next
+                },
+                Opcode::SetGlobal | Opcode::SetGlobalLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 1, opcode.is_long());
+                    let val = self.peek(0)?;
+                    // Assignment never creates a global; undo the insert if it did
+                    if self.globals.insert(name, val).is_none() {
+                        self.globals.delete(&name);
+                        return Err(self.undefined_variable(name));
+                    }
+                    next
                 },
                 Opcode::Constant => {
-                    let addr = chunk.code[self.ip + 1] as usize;
-                    let constant = &chunk.constants[addr];
-                    self.push((*constant).clone()); // TODO: how does this work with strings
-                    self.ip + 2
-                },
-                Opcode::Nil => { self.push(Value::Nil); self.ip + 1 },
-                Opcode::True => { self.push(Value::Bool(true)); self.ip + 1 },
-                Opcode::False => { self.push(Value::Bool(false)); self.ip + 1 },
-                Opcode::Neg => self.unary_op(chunk, &std::ops::Neg::neg)?,
+                    let addr = code[*ip + 1] as usize;
+                    self.push(constants[addr])?;
+                    *ip + 2
+                },
+                Opcode::ConstantLong => {
+                    let addr = chunk.read_u24(*ip + 1);
+                    self.push(constants[addr])?;
+                    *ip + 4
+                },
+                Opcode::Nil => { self.push(Value::NIL)?; *ip + 1 },
+                Opcode::True => { self.push(Value::bool(true))?; *ip + 1 },
+                Opcode::False => { self.push(Value::bool(false))?; *ip + 1 },
+                Opcode::Neg => {
+                    let operand = self.operand()?;
+                    match operand.as_number() {
+                        Some(n) => *operand = Value::number(-n),
+                        None => return Err(Fault::new(RuntimeErrorKind::Type, "Operand must be a number")),
+                    }
+                    *ip + 1
+                },
                 Opcode::Not => {
-                    let val = self.pop()?;
-                    self.push(Value::Bool(self.is_falsey(val)));
-                    self.ip + 1
-                },
-                Opcode::Add => self.add(chunk)?,
-                Opcode::Sub => self.binary_op(chunk, &std::ops::Sub::sub)?,
-                Opcode::Mul => self.binary_op(chunk, &std::ops::Mul::mul)?,
-                Opcode::Div => self.binary_op(chunk, &std::ops::Div::div)?,
-                Opcode::Equal => self.eq(chunk)?,
-                Opcode::Greater => self.cmp(chunk, std::cmp::PartialOrd::gt)?,
-                Opcode::Less => self.cmp(chunk, std::cmp::PartialOrd::lt)?,
-                _ => return Err(InterpretError::RuntimeError),
+                    let operand = self.operand()?;
+                    *operand = Value::bool(operand.is_falsey());
+                    *ip + 1
+                },
+                Opcode::Add => {
+                    let (lhs, rhs) = self.operands()?;
+                    match (lhs.as_number(), rhs.as_number()) {
+                        (Some(lhs), Some(rhs)) => self.replace_operands(Value::number(lhs + rhs)),
+                        _ => {
+                            let concat = self.concatenate(lhs, rhs)?;
+                            self.replace_operands(concat);
+                            self.maybe_collect();
+                        },
+                    }
+                    *ip + 1
+                },
+                Opcode::Sub => binary_op!(Value::number, -),
+                Opcode::Mul => binary_op!(Value::number, *),
+                Opcode::Div => binary_op!(Value::number, /),
+                Opcode::Greater => binary_op!(Value::bool, >),
+                Opcode::Less => binary_op!(Value::bool, <),
+                Opcode::GreaterEqual => binary_op!(Value::bool, >=),
+                Opcode::LessEqual => binary_op!(Value::bool, <=),
+                // Strings are interned, so objects are equal only if they are the same object
+                Opcode::Equal => {
+                    let (lhs, rhs) = self.operands()?;
+                    self.replace_operands(Value::bool(lhs == rhs));
+                    *ip + 1
+                },
+                Opcode::NotEqual => {
+                    let (lhs, rhs) = self.operands()?;
+                    self.replace_operands(Value::bool(lhs != rhs));
+                    *ip + 1
+                },
+                Opcode::Error => return Err(Fault::new(RuntimeErrorKind::UnknownOpcode, "Unknown opcode")),
+            }
+        }
+    }
+
+    // Run the register code of the innermost frame, as `run_frame` runs
+    // stack code. The frame's registers are the stack from index `slots` on
+    fn run_registers(&mut self, chunk: &Chunk, closure: ObjRef, slots: usize, ip: &mut usize) -> Result<Next, Fault> {
+        let code = &chunk.code[..];
+        let constants = &chunk.constants[..];
+
+        // The register named by the operand byte at `offset`
+        macro_rules! reg {
+            ($offset:expr) => { self.stack[slots + code[$offset] as usize] };
+        }
+
+        // Apply an arithmetic operator to two numbers in registers
+        macro_rules! binary_op {
+            ($result:path, $op:tt) => {{
+                match (reg!(*ip + 2).as_number(), reg!(*ip + 3).as_number()) {
+                    (Some(lhs), Some(rhs)) => reg!(*ip + 1) = $result(lhs $op rhs),
+                    _ => return Err(Fault::new(RuntimeErrorKind::Type, "Operands must be numbers")),
+                }
+                *ip + 4
+            }};
+        }
+
+        loop {
+            if DEBUG {
+                // Print the frame's registers
+                print!("\t");
+                for value in &self.stack[slots..] {
+                    print!("[ {} ]", value.display(&self.heap));
+                }
+                println!();
+                register::disassemble_instruction(chunk, *ip, &self.heap);
+            }
+            let op = Op::from(code[*ip]);
+            *ip = match op {
+                Op::Move => { reg!(*ip + 1) = reg!(*ip + 2); *ip + 3 },
+                Op::LoadConstant => { reg!(*ip + 1) = constants[code[*ip + 2] as usize]; *ip + 3 },
+                Op::LoadConstantLong => { reg!(*ip + 1) = constants[chunk.read_u24(*ip + 2)]; *ip + 5 },
+                Op::LoadNil => { reg!(*ip + 1) = Value::NIL; *ip + 2 },
+                Op::DefineGlobal | Op::DefineGlobalLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 2, op.is_long());
+                    self.globals.insert(name, reg!(*ip + 1));
+                    next
+                },
+                Op::GetGlobal | Op::GetGlobalLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 2, op.is_long());
+                    match self.globals.get(&name) {
+                        Some(&val) => reg!(*ip + 1) = val,
+                        None => return Err(self.undefined_variable(name)),
+                    }
+                    next
+                },
+                Op::SetGlobal | Op::SetGlobalLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 2, op.is_long());
+                    // Assignment never creates a global; undo the insert if it did
+                    if self.globals.insert(name, reg!(*ip + 1)).is_none() {
+                        self.globals.delete(&name);
+                        return Err(self.undefined_variable(name));
+                    }
+                    next
+                },
+                Op::GetUpvalue => {
+                    let upvalue = self.heap.closure(closure).upvalues[code[*ip + 2] as usize];
+                    reg!(*ip + 1) = match self.heap.upvalue(upvalue) {
+                        Upvalue::Open(slot) => self.stack[*slot],
+                        Upvalue::Closed(val) => *val,
+                    };
+                    *ip + 3
+                },
+                Op::SetUpvalue => {
+                    let val = reg!(*ip + 1);
+                    let upvalue = self.heap.closure(closure).upvalues[code[*ip + 2] as usize];
+                    match self.heap.upvalue_mut(upvalue) {
+                        Upvalue::Open(slot) => self.stack[*slot] = val,
+                        Upvalue::Closed(closed) => *closed = val,
+                    }
+                    *ip + 3
+                },
+                Op::Close => {
+                    self.close_upvalues(slots + code[*ip + 1] as usize);
+                    *ip + 2
+                },
+                Op::Neg => {
+                    match reg!(*ip + 2).as_number() {
+                        Some(n) => reg!(*ip + 1) = Value::number(-n),
+                        None => return Err(Fault::new(RuntimeErrorKind::Type, "Operand must be a number")),
+                    }
+                    *ip + 3
+                },
+                Op::Not => { reg!(*ip + 1) = Value::bool(reg!(*ip + 2).is_falsey()); *ip + 3 },
+                Op::Add => {
+                    let (lhs, rhs) = (reg!(*ip + 2), reg!(*ip + 3));
+                    match (lhs.as_number(), rhs.as_number()) {
+                        (Some(lhs), Some(rhs)) => reg!(*ip + 1) = Value::number(lhs + rhs),
+                        _ => {
+                            reg!(*ip + 1) = self.concatenate(lhs, rhs)?;
+                            self.maybe_collect();
+                        },
+                    }
+                    *ip + 4
+                },
+                Op::Sub => binary_op!(Value::number, -),
+                Op::Mul => binary_op!(Value::number, *),
+                Op::Div => binary_op!(Value::number, /),
+                Op::Greater => binary_op!(Value::bool, >),
+                Op::GreaterEqual => binary_op!(Value::bool, >=),
+                Op::Less => binary_op!(Value::bool, <),
+                Op::LessEqual => binary_op!(Value::bool, <=),
+                Op::Equal => { reg!(*ip + 1) = Value::bool(reg!(*ip + 2) == reg!(*ip + 3)); *ip + 4 },
+                Op::NotEqual => { reg!(*ip + 1) = Value::bool(reg!(*ip + 2) != reg!(*ip + 3)); *ip + 4 },
+                Op::Jump => *ip + 3 + chunk.read_u16(*ip + 1) as usize,
+                Op::JumpIfFalse => {
+                    if reg!(*ip + 1).is_falsey() { *ip + 4 + chunk.read_u16(*ip + 2) as usize } else { *ip + 4 }
+                },
+                Op::JumpIfTrue => {
+                    if reg!(*ip + 1).is_falsey() { *ip + 4 } else { *ip + 4 + chunk.read_u16(*ip + 2) as usize }
+                },
+                Op::Loop => *ip + 3 - chunk.read_u16(*ip + 1) as usize,
+                Op::Print => {
+                    if writeln!(self.out, "{}", reg!(*ip + 1).display(&self.heap)).is_err() {
+                        return Err(Fault::new(RuntimeErrorKind::Output, "Could not write output"));
+                    }
+                    *ip + 2
+                },
+                Op::Call => {
+                    let (base, arg_count) = (slots + code[*ip + 1] as usize, code[*ip + 2] as usize);
+                    // Save the return address before switching frames
+                    self.frame_mut().ip = *ip + 3;
+                    self.call_value(base, arg_count)?;
+                    return Ok(Next::Frame);
+                },
+                Op::Invoke | Op::InvokeLong => {
+                    let base = slots + code[*ip + 1] as usize;
+                    let (name, next) = Self::read_name(chunk, *ip + 2, op.is_long());
+                    self.frame_mut().ip = next + 1;
+                    self.invoke(name, base, code[next] as usize)?;
+                    return Ok(Next::Frame);
+                },
+                Op::SuperInvoke | Op::SuperInvokeLong => {
+                    let base = slots + code[*ip + 1] as usize;
+                    let (name, next) = Self::read_name(chunk, *ip + 2, op.is_long());
+                    let superclass = self.as_class(reg!(next + 1)).expect("'super' must be a class");
+                    self.frame_mut().ip = next + 2;
+                    self.invoke_from_class(superclass, name, base, code[next] as usize)?;
+                    return Ok(Next::Frame);
+                },
+                Op::Closure | Op::ClosureLong => {
+                    let (addr, next) = chunk.read_constant(*ip + 2, op.is_long());
+                    let function = constants[addr].as_obj().expect("Closure constant must be a function");
+                    let function = Rc::clone(self.heap.function(function));
+
+                    let mut offset = next;
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = code[offset] == 1;
+                        let index = code[offset + 1] as usize;
+                        upvalues.push(if is_local {
+                            self.capture_upvalue(slots + index)
+                        } else {
+                            self.heap.closure(closure).upvalues[index]
+                        });
+                        offset += 2;
+                    }
+
+                    let closure = self.heap.alloc(Obj::Closure(Closure { function, upvalues }));
+                    reg!(*ip + 1) = Value::obj(closure);
+                    self.maybe_collect();
+                    offset
+                },
+                Op::Return => {
+                    let result = reg!(*ip + 1);
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    if self.frames.is_empty() {
+                        self.stack.truncate(frame.slots);
+                        return Ok(Next::Done);
+                    }
+                    // The result takes the callee's place in the caller's registers
+                    self.stack[frame.slots] = result;
+                    let caller = self.frame();
+                    self.resize_window(caller.slots + caller.function.registers)?;
+                    return Ok(Next::Frame);
+                },
+                Op::Class | Op::ClassLong => {
+                    let (name, next) = Self::read_name(chunk, *ip + 2, op.is_long());
+                    let name = self.heap.string(name).to_owned();
+                    let class = self.heap.alloc(Obj::Class(Class { name, methods: Table::default() }));
+                    reg!(*ip + 1) = Value::obj(class);
+                    self.maybe_collect();
+                    next
+                },
+                Op::Inherit => {
+                    let superclass = match self.as_class(reg!(*ip + 2)) {
+                        Some(superclass) => superclass,
+                        None => return Err(Fault::new(RuntimeErrorKind::Type, "Superclass must be a class")),
+                    };
+                    let subclass = self.as_class(reg!(*ip + 1)).expect("Only classes inherit");
+                    // Copy-down inheritance: methods the subclass
+                    // defines later override the copied ones
+                    let mut methods = mem::take(&mut self.heap.class_mut(subclass).methods);
+                    self.heap.class(superclass).methods.add_all(&mut methods);
+                    self.heap.class_mut(subclass).methods = methods;
+                    *ip + 3
+                },
+                Op::Method | Op::MethodLong => {
+                    let class = self.as_class(reg!(*ip + 1)).expect("Methods are only defined on classes");
+                    let (name, next) = Self::read_name(chunk, *ip + 2, op.is_long());
+                    let method = reg!(next);
+                    self.heap.class_mut(class).methods.insert(name, method);
+                    next + 1
+                },
+                Op::GetProperty | Op::GetPropertyLong => {
+                    let receiver = reg!(*ip + 2);
+                    let instance = match self.as_instance(receiver) {
+                        Some(instance) => self.heap.instance(instance),
+                        None => return Err(Fault::new(RuntimeErrorKind::Type, "Only instances have properties")),
+                    };
+                    let (name, next) = Self::read_name(chunk, *ip + 3, op.is_long());
+                    match instance.fields.get(&name) {
+                        Some(&val) => reg!(*ip + 1) = val,
+                        None => {
+                            reg!(*ip + 1) = self.bind_method(instance.class, name, receiver)?;
+                            self.maybe_collect();
+                        },
+                    }
+                    next
+                },
+                Op::SetProperty | Op::SetPropertyLong => {
+                    let instance = match self.as_instance(reg!(*ip + 1)) {
+                        Some(instance) => instance,
+                        None => return Err(Fault::new(RuntimeErrorKind::Type, "Only instances have fields")),
+                    };
+                    let (name, next) = Self::read_name(chunk, *ip + 2, op.is_long());
+                    let val = reg!(next);
+                    self.heap.instance_mut(instance).fields.insert(name, val);
+                    next + 1
+                },
+                Op::GetSuper | Op::GetSuperLong => {
+                    let superclass = self.as_class(reg!(*ip + 3)).expect("'super' must be a class");
+                    let (name, next) = Self::read_name(chunk, *ip + 4, op.is_long());
+                    reg!(*ip + 1) = self.bind_method(superclass, name, reg!(*ip + 2))?;
+                    self.maybe_collect();
+                    next
+                },
+                Op::Error => return Err(Fault::new(RuntimeErrorKind::UnknownOpcode, "Unknown opcode")),
+            }
+        }
+    }
+}
+
+// What the run loop does after a frame stops running
+enum Next {
+    Frame, // Run the frame that's now innermost
+    Done,  // The script returned
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    use crate::chunk::{Chunk, Opcode, Source};
+    use crate::heap::Heap;
+    use crate::value::Value;
+    use crate::compiler::{Diagnostic, ErrorToken};
+    use crate::token::Span;
+    use crate::vm::{Backend, VM, InterpretError, RuntimeError, RuntimeErrorKind};
+
+    const BACKENDS: [Backend; 2] = [Backend::Stack, Backend::Register];
+
+    fn vm_with(backend: Backend, out: Output) -> VM {
+        let mut vm = VM::with_output(out);
+        vm.set_backend(backend);
+        vm
+    }
+
+    // A cloneable sink so tests can read back what the VM printed
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // How a program failed, for tests that don't care about the details
+    #[derive(Debug, PartialEq)]
+    enum Failure {
+        Compile,
+        Runtime,
+    }
+
+    fn failure<T>(result: Result<T, InterpretError>) -> Result<T, Failure> {
+        result.map_err(|error| match error {
+            InterpretError::CompileError(_) => Failure::Compile,
+            InterpretError::RuntimeError(_) => Failure::Runtime,
+        })
+    }
+
+    fn run_with(source: &str, backend: Backend, gc_stress: bool, optimize: bool) -> Result<String, InterpretError> {
+        let out = Output::default();
+        let mut vm = vm_with(backend, out.clone());
+        vm.set_gc_stress(gc_stress);
+        vm.set_optimize(optimize);
+        vm.interpret(source)?;
+        let bytes = out.0.borrow().clone();
+        Ok(String::from_utf8(bytes).unwrap())
+    }
+
+    // Run a program on both backends, checking that they agree on
+    // its output or on every detail of the error that stopped it
+    fn run_on_both(source: &str) -> Result<String, InterpretError> {
+        let result = run_with(source, Backend::Stack, false, true);
+        assert_eq!(run_with(source, Backend::Register, false, true), result, "on the register backend");
+        result
+    }
+
+    // Run a program, checking that neither collecting garbage as often as
+    // possible, nor optimizing the code, nor the backend changes what it does
+    fn run(source: &str) -> Result<String, Failure> {
+        let result = run_on_both(source);
+        for &backend in BACKENDS.iter() {
+            assert_eq!(run_with(source, backend, true, true), result, "under GC stress on {:?}", backend);
+        }
+        assert_eq!(run_with(source, Backend::Stack, false, false), result, "unoptimized");
+        failure(result)
+    }
+
+    fn error_kind(result: Result<(), InterpretError>) -> Option<RuntimeErrorKind> {
+        match result {
+            Err(InterpretError::RuntimeError(error)) => Some(error.kind),
+            _ => None,
+        }
+    }
+
+    fn diagnostics(source: &str) -> Vec<Diagnostic> {
+        match run_on_both(source) {
+            Err(InterpretError::CompileError(diagnostics)) => diagnostics,
+            result => panic!("Expected a compile error, got {:?}", result),
+        }
+    }
+
+    fn runtime_error(source: &str) -> RuntimeError {
+        match run_on_both(source) {
+            Err(InterpretError::RuntimeError(error)) => error,
+            result => panic!("Expected a runtime error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn print_statements() {
+        assert_eq!(run("print 1 + 2; print \"a\" + \"b\"; print !nil;"),
+                   Ok("3\nab\ntrue\n".to_owned()));
+    }
+
+    #[test]
+    fn expression_statements_are_discarded() {
+        assert_eq!(run("1 + 2; \"unused\"; print 3;"), Ok("3\n".to_owned()));
+        assert_eq!(run(""), Ok("".to_owned()));
+    }
+
+    #[test]
+    fn string_equality() {
+        // Literals, concatenation results and native results are all interned
+        assert_eq!(run("var a = \"ab\"; print a == \"a\" + \"b\"; print str(12) == \"1\" + \"2\";"),
+                   Ok("true\ntrue\n".to_owned()));
+        assert_eq!(run("print \"a\" == \"b\"; print \"1\" == 1;"), Ok("false\nfalse\n".to_owned()));
+    }
+
+    #[test]
+    fn global_variables() {
+        assert_eq!(run("var a = 1; var b; print a; print b; a = b = 2; print a + b;"),
+                   Ok("1\nnil\n4\n".to_owned()));
+        assert_eq!(run("var s = \"x\"; s = s + s; print s;"), Ok("xx\n".to_owned()));
+    }
+
+    #[test]
+    fn globals_persist_across_interpret_calls() {
+        for &backend in BACKENDS.iter() {
+            let out = Output::default();
+            let mut vm = vm_with(backend, out.clone());
+            assert_eq!(vm.interpret("var a = 1;"), Ok(()));
+            assert_eq!(vm.interpret("a = a + 1;"), Ok(()));
+            assert_eq!(vm.interpret("print a;"), Ok(()));
+            assert_eq!(*out.0.borrow(), b"2\n");
+        }
+    }
+
+    #[test]
+    fn undefined_globals() {
+        assert_eq!(run("print a;"), Err(Failure::Runtime));
+        assert_eq!(run("a = 1;"), Err(Failure::Runtime));
+        // A failed assignment must not define the variable
+        for &backend in BACKENDS.iter() {
+            let mut vm = vm_with(backend, Output::default());
+            assert_eq!(failure(vm.interpret("a = 1;")), Err(Failure::Runtime));
+            assert_eq!(failure(vm.interpret("print a;")), Err(Failure::Runtime));
+        }
+    }
+
+    #[test]
+    fn local_variables() {
+        assert_eq!(run("var a = \"global\"; { var a = \"outer\"; { var a = \"inner\"; print a; } print a; } print a;"),
+                   Ok("inner\nouter\nglobal\n".to_owned()));
+        assert_eq!(run("{ var a = 1; var b = 2; a = b = a + b; print a; print b; }"),
+                   Ok("3\n3\n".to_owned()));
+        // The shadowed local's initializer can see the outer variable
+        assert_eq!(run("var a = 1; { var b = a + 1; { var a = b; print a; } }"),
+                   Ok("2\n".to_owned()));
+    }
+
+    #[test]
+    fn scope_exit_pops_locals() {
+        // If the block's locals weren't popped, `b` would read the wrong slot
+        assert_eq!(run("{ { var a = 1; var b = 2; } var c = 3; print c; }"), Ok("3\n".to_owned()));
+    }
+
+    #[test]
+    fn local_declaration_errors() {
+        assert_eq!(run("{ var a = 1; var a = 2; }"), Err(Failure::Compile));
+        assert_eq!(run("{ var a = a; }"), Err(Failure::Compile));
+        assert_eq!(run("{ var a = 1; { var a = a; } }"), Err(Failure::Compile));
+        assert_eq!(run("{ print 1;"), Err(Failure::Compile));
+    }
+
+    #[test]
+    fn if_else() {
+        assert_eq!(run("if (true) print 1; else print 2; if (nil) print 3; else print 4;"),
+                   Ok("1\n4\n".to_owned()));
+        assert_eq!(run("if (0) { print \"zero is truthy\"; } if (false) print 5;"),
+                   Ok("zero is truthy\n".to_owned()));
+        // A dangling else binds to the nearest if
+        assert_eq!(run("if (true) if (false) print 1; else print 2;"), Ok("2\n".to_owned()));
+    }
+
+    #[test]
+    fn logical_operators() {
+        assert_eq!(run("print 1 and 2; print nil and 2; print false or \"b\"; print 1 or 2;"),
+                   Ok("2\nnil\nb\n1\n".to_owned()));
+        // The right operand must not be evaluated when short-circuiting
+        assert_eq!(run("var a = 1; false and (a = 2); true or (a = 3); print a;"),
+                   Ok("1\n".to_owned()));
+    }
+
+    #[test]
+    fn while_loop() {
+        assert_eq!(run("var i = 0; while (i < 3) { print i; i = i + 1; }"),
+                   Ok("0\n1\n2\n".to_owned()));
+    }
+
+    #[test]
+    fn for_loop() {
+        assert_eq!(run("for (var i = 0; i < 3; i = i + 1) print i;"),
+                   Ok("0\n1\n2\n".to_owned()));
+        assert_eq!(run("var i = 0; for (; i < 2;) { print i; i = i + 1; } print i;"),
+                   Ok("0\n1\n2\n".to_owned()));
+        // The loop variable is scoped to the loop
+        assert_eq!(run("for (var i = 0; i < 1; i = i + 1) {} print i;"),
+                   Err(Failure::Runtime));
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(run("fun add(a, b) { return a + b; } print add(1, 2); print add;"),
+                   Ok("3\n<fn add>\n".to_owned()));
+        assert_eq!(run("fun f() {} print f();"), Ok("nil\n".to_owned()));
+        assert_eq!(run("fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); } print fib(10);"),
+                   Ok("55\n".to_owned()));
+        // Locals in a function body live in that call's frame
+        assert_eq!(run("var a = 1; fun f(a) { var b = a * 2; { var c = b; return c; } } print f(5) + a;"),
+                   Ok("11\n".to_owned()));
+    }
+
+    #[test]
+    fn local_functions() {
+        assert_eq!(run("{ fun f(n) { if (n > 0) return f(n - 1); return \"done\"; } print f(3); }"),
+                   Ok("done\n".to_owned()));
+        assert_eq!(run("{ fun f(n) { return n + 1; } print f(1); }"), Ok("2\n".to_owned()));
+    }
+
+    #[test]
+    fn closures() {
+        let counter = "
+            fun makeCounter() {
+                var count = 0;
+                fun increment() { count = count + 1; return count; }
+                return increment;
+            }
+            var a = makeCounter();
+            var b = makeCounter();
+            print a(); print a(); print b(); print a();
+        ";
+        assert_eq!(run(counter), Ok("1\n2\n1\n3\n".to_owned()));
+
+        // Closures capturing the same variable share it, even once it's closed
+        let shared = "
+            var get; var set;
+            { var x = \"before\"; fun g() { return x; } fun s(v) { x = v; } get = g; set = s; }
+            set(\"after\"); print get();
+        ";
+        assert_eq!(run(shared), Ok("after\n".to_owned()));
+
+        // Variables are captured through several enclosing functions
+        let nested = "
+            fun outer() {
+                var x = \"outer\";
+                fun middle() { fun inner() { return x; } return inner; }
+                return middle;
+            }
+            print outer()()();
+        ";
+        assert_eq!(run(nested), Ok("outer\n".to_owned()));
+
+        // Captures see the variable, not a copy of its value at capture time
+        assert_eq!(run("{ var a = 1; fun f() { return a; } a = 2; print f(); }"), Ok("2\n".to_owned()));
+    }
+
+    #[test]
+    fn closures_in_loops() {
+        let for_loop = "
+            var fs0; var fs1; var fs2;
+            for (var i = 0; i < 3; i = i + 1) {
+                fun f() { return i; }
+                if (i == 0) fs0 = f; if (i == 1) fs1 = f; if (i == 2) fs2 = f;
+            }
+            print fs0(); print fs1(); print fs2();
+        ";
+        assert_eq!(run(for_loop), Ok("0\n1\n2\n".to_owned()));
+
+        let while_loop = "
+            var fs0; var fs1; var i = 0;
+            while (i < 2) {
+                var j = i;
+                fun f() { return j; }
+                if (i == 0) fs0 = f; else fs1 = f;
+                i = i + 1;
+            }
+            print fs0(); print fs1();
+        ";
+        assert_eq!(run(while_loop), Ok("0\n1\n".to_owned()));
+
+        // Assigning to the loop variable in the body still affects the loop
+        assert_eq!(run("for (var i = 0; i < 5; i = i + 1) { print i; i = i + 2; }"),
+                   Ok("0\n3\n".to_owned()));
+    }
+
+    #[test]
+    fn classes_and_fields() {
+        assert_eq!(run("class Point {} print Point; var p = Point(); print p;"),
+                   Ok("Point\nPoint instance\n".to_owned()));
+        assert_eq!(run("class P {} var p = P(); p.x = 1; p.y = p.x + 1; print p.x + p.y; print p.y = 5;"),
+                   Ok("3\n5\n".to_owned()));
+        // Instances are shared, not copied, when passed around
+        assert_eq!(run("class Box {} fun fill(b) { b.value = \"full\"; } var b = Box(); fill(b); print b.value;"),
+                   Ok("full\n".to_owned()));
+        assert_eq!(run("class A {} var a = A(); var b = A(); print a == a; print a == b;"),
+                   Ok("true\nfalse\n".to_owned()));
+    }
+
+    #[test]
+    fn methods_and_this() {
+        let source = "
+            class Counter {
+                init(start) { this.count = start; }
+                increment() { this.count = this.count + 1; return this; }
+                get() { return this.count; }
+            }
+            var c = Counter(10);
+            print c.increment().increment().get();
+            print c.get;
+        ";
+        assert_eq!(run(source), Ok("12\n<fn get>\n".to_owned()));
+
+        // `this` is captured by closures inside methods
+        let closure = "
+            class Greeter {
+                init(name) { this.name = name; }
+                greeter() { fun greet() { return \"hi \" + this.name; } return greet; }
+            }
+            print Greeter(\"bob\").greeter()();
+        ";
+        assert_eq!(run(closure), Ok("hi bob\n".to_owned()));
+    }
+
+    #[test]
+    fn initializers() {
+        // An initializer returns the instance, even when called again directly
+        assert_eq!(run("class A { init() { this.x = 1; return; } } var a = A(); print a.init() == a; print a.x;"),
+                   Ok("true\n1\n".to_owned()));
+        assert_eq!(run("class A { init(a, b) {} } A(1);"), Err(Failure::Runtime));
+        assert_eq!(run("class A { init() { return 1; } }"), Err(Failure::Compile));
+    }
+
+    #[test]
+    fn bound_methods() {
+        let source = "
+            class Person {
+                init(name) { this.name = name; }
+                sayName() { print this.name; }
+            }
+            var jane = Person(\"Jane\");
+            var method = jane.sayName;
+            jane.name = \"Janet\";
+            method();
+            var bill = Person(\"Bill\");
+            bill.sayName = method;
+            bill.sayName();
+        ";
+        assert_eq!(run(source), Ok("Janet\nJanet\n".to_owned()));
+    }
+
+    #[test]
+    fn inheritance() {
+        let source = "
+            class A {
+                method() { return \"A method\"; }
+                inherited() { return \"inherited\"; }
+            }
+            class B < A {
+                method() { return \"B method\"; }
+            }
+            var b = B();
+            print b.method();
+            print b.inherited();
+        ";
+        assert_eq!(run(source), Ok("B method\ninherited\n".to_owned()));
+
+        // Initializers are inherited too
+        assert_eq!(run("class A { init(x) { this.x = x; } } class B < A {} print B(3).x;"),
+                   Ok("3\n".to_owned()));
+    }
+
+    #[test]
+    fn super_calls() {
+        let source = "
+            class A {
+                init(x) { this.x = x; }
+                describe() { return \"A\" + this.x; }
+            }
+            class B < A {
+                init(x) { super.init(x + \"!\"); }
+                describe() { return \"B\" + super.describe(); }
+            }
+            class C < B {
+                describe() { var m = super.describe; return \"C\" + m(); }
+            }
+            print C(\"x\").describe();
+        ";
+        assert_eq!(run(source), Ok("CBAx!\n".to_owned()));
+
+        // `super` is resolved statically, not from the receiver's class
+        let static_super = "
+            class A { say() { return \"A\"; } }
+            class B < A { test() { return super.say(); } say() { return \"B\"; } }
+            class C < B { say() { return \"C\"; } }
+            print C().test();
+        ";
+        assert_eq!(run(static_super), Ok("A\n".to_owned()));
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        assert_eq!(run("var NotClass = 1; class A < NotClass {}"), Err(Failure::Runtime));
+        assert_eq!(run("class A < A {}"), Err(Failure::Compile));
+        assert_eq!(run("class A { f() { return super.f(); } }"), Err(Failure::Compile));
+        assert_eq!(run("fun f() { return super.f(); }"), Err(Failure::Compile));
+        assert_eq!(run("class A {} class B < A { f() { return super.missing(); } } B().f();"),
+                   Err(Failure::Runtime));
+    }
+
+    #[test]
+    fn this_outside_class() {
+        assert_eq!(run("print this;"), Err(Failure::Compile));
+        assert_eq!(run("fun f() { return this; }"), Err(Failure::Compile));
+    }
+
+    #[test]
+    fn property_errors() {
+        assert_eq!(run("class A {} print A().missing;"), Err(Failure::Runtime));
+        assert_eq!(run("var a = 1; print a.field;"), Err(Failure::Runtime));
+        assert_eq!(run("var a = \"s\"; a.field = 1;"), Err(Failure::Runtime));
+        assert_eq!(run("class A {} A(1);"), Err(Failure::Runtime));
+        assert_eq!(run("class A {} A().missing();"), Err(Failure::Runtime));
+        assert_eq!(run("var a = 1; a.method();"), Err(Failure::Runtime));
+    }
+
+    #[test]
+    fn natives() {
+        assert_eq!(run("print clock() > 0; print clock;"), Ok("true\n<native fn>\n".to_owned()));
+        assert_eq!(run("print sqrt(16); print len(\"four\"); print str(1 + 2) + \"!\";"),
+                   Ok("4\n4\n3!\n".to_owned()));
+        // Natives are first-class values like Lox functions
+        assert_eq!(run("fun apply(f, x) { return f(x); } print apply(sqrt, 9);"), Ok("3\n".to_owned()));
+    }
+
+    #[test]
+    fn native_errors() {
+        assert_eq!(run("clock(1);"), Err(Failure::Runtime));
+        assert_eq!(run("sqrt();"), Err(Failure::Runtime));
+        assert_eq!(run("sqrt(\"a\");"), Err(Failure::Runtime));
+    }
+
+    #[test]
+    fn define_native() {
+        fn add(_: &mut Heap, args: &[Value]) -> Result<Value, String> {
+            match (args[0].as_number(), args[1].as_number()) {
+                (Some(a), Some(b)) => Ok(Value::number(a + b)),
+                _ => Err("add() expects numbers".to_owned()),
             }
         }
+
+        for &backend in BACKENDS.iter() {
+            let out = Output::default();
+            let mut vm = vm_with(backend, out.clone());
+            vm.define_native("add", 2, add);
+            assert_eq!(vm.interpret("print add(1, add(2, 3));"), Ok(()));
+            assert_eq!(failure(vm.interpret("add(1, nil);")), Err(Failure::Runtime));
+            assert_eq!(*out.0.borrow(), b"6\n");
+        }
+    }
+
+    #[test]
+    fn call_errors() {
+        assert_eq!(run("fun f(a) {} f();"), Err(Failure::Runtime));
+        assert_eq!(run("fun f() {} f(1, 2);"), Err(Failure::Runtime));
+        assert_eq!(run("var a = 1; a();"), Err(Failure::Runtime));
+        assert_eq!(run("return 1;"), Err(Failure::Compile));
+    }
+
+    #[test]
+    fn stack_overflow() {
+        for &backend in BACKENDS.iter() {
+            let out = Output::default();
+            let mut vm = vm_with(backend, out.clone());
+            vm.set_stack_size(16);
+            // Each call takes at least five slots, so the stack fills up long before the call depth limit
+            let source = "fun f(a, b, c, d) { return f(a, b, c, d); } f(1, 2, 3, 4);";
+            assert_eq!(error_kind(vm.interpret(source)), Some(RuntimeErrorKind::StackOverflow));
+            // Programs that fit still run
+            assert_eq!(vm.interpret("fun f(a, b) { return a + b; } print f(1, 2);"), Ok(()));
+            assert_eq!(*out.0.borrow(), b"3\n");
+        }
+    }
+
+    #[test]
+    fn call_depth_exceeded() {
+        // Calls run out before the stack does, and say so
+        let error = runtime_error("fun f() { f(); } f();");
+        assert_eq!(error.kind, RuntimeErrorKind::CallDepthExceeded);
+        assert_eq!(error.message, "Too many nested calls");
+    }
+
+    #[test]
+    fn thousands_of_constants() {
+        // Past the 256th constant, literals need a wider operand.
+        // Starting from a variable keeps the sum from being folded
+        let terms: Vec<String> = (0..3000).map(|i| i.to_string()).collect();
+        let source = format!("var x = 0; print x + {}; print \"last\";", terms.join(" + "));
+        assert_eq!(run(&source), Ok("4498500\nlast\n".to_owned()));
+    }
+
+    #[test]
+    fn names_past_the_256th_constant() {
+        // Every name first used after the literals, in the script and in the
+        // methods alike, needs a wide operand
+        let literals: String = (0..300).map(|i| format!("n = n + {};", i)).collect();
+        let source = format!("
+            var n = 0; {literals}
+            class A {{ init(x) {{ this.x = x; }} get() {{ return this.x; }} }}
+            class B < A {{
+                get() {{ var n = 0; {literals} return super.get() + n; }}
+                sum() {{ var n = 0; {literals} var get = super.get; return get() + this.get(); }}
+            }}
+            fun f(b) {{ return b.get(); }}
+            var b = B(1);
+            b.x = b.x + 1;
+            var c = 0;
+            c = f(b);
+            print c;
+            print b.sum();
+            print n;", literals = literals);
+        assert_eq!(run(&source), Ok("44852\n44854\n44850\n".to_owned()));
+    }
+
+    #[test]
+    fn repeated_names_share_a_constant() {
+        // Each use of `a` would otherwise take another slot in the pool
+        let source = format!("var a = 0; {} print a;", "a = a + 1;".repeat(300));
+        assert_eq!(run(&source), Ok("300\n".to_owned()));
+    }
+
+    #[test]
+    fn nested_constants_beside_hundreds_of_locals() {
+        // Unfolded, each level of nesting would take another register
+        let locals: String = (0..240).map(|i| format!("var v{} = {};", i, i)).collect();
+        let nested = (1..17).rev().fold("17".to_owned(), |inner, i| format!("({} + {})", i, inner));
+        let source = format!("fun f() {{ {} print v0 + v239 + {}; }} f();", locals, nested);
+        assert_eq!(run(&source), Ok("392\n".to_owned()));
+    }
+
+    #[test]
+    fn stack_underflow() {
+        // The script's closure is in slot 0, so the second pop underflows
+        let mut chunk = Chunk::new();
+        for _ in 0..2 {
+            chunk.write(Opcode::Pop.into(), Span::default());
+        }
+        chunk.write(Opcode::Return.into(), Span::default());
+        let mut vm = VM::with_output(Output::default());
+        assert_eq!(error_kind(vm.interpret_chunk(chunk)), Some(RuntimeErrorKind::StackUnderflow));
+    }
+
+    #[test]
+    fn functions_only_run_on_the_backend_they_were_compiled_for() {
+        for &(from, to) in &[(Backend::Stack, Backend::Register), (Backend::Register, Backend::Stack)] {
+            let out = Output::default();
+            let mut vm = vm_with(from, out.clone());
+            assert_eq!(vm.interpret("fun f(a, b) { var c = a + b; return c * 2; } var n = 1;"), Ok(()));
+            vm.set_backend(to);
+            assert_eq!(error_kind(vm.interpret("print f(1, 2);")), Some(RuntimeErrorKind::WrongBackend));
+            // Values other than functions carry over
+            assert_eq!(vm.interpret("fun g(a, b) { return a + b; } print g(n, 2);"), Ok(()));
+            assert_eq!(*out.0.borrow(), b"3\n");
+        }
+
+        // Hand-written chunks are stack code
+        let mut chunk = Chunk::new();
+        chunk.write(Opcode::Nil.into(), Span::default());
+        chunk.write(Opcode::Return.into(), Span::default());
+        let mut vm = vm_with(Backend::Register, Output::default());
+        assert_eq!(error_kind(vm.interpret_chunk(chunk)), Some(RuntimeErrorKind::WrongBackend));
+    }
+
+    #[test]
+    fn peeking_into_an_empty_stack() {
+        // Each instruction looks at the top of the stack after the script's closure is popped
+        let instructions: [&[u8]; 6] = [
+            &[Opcode::SetGlobal.into(), 0],
+            &[Opcode::SetLocal.into(), 0],
+            &[Opcode::SetUpvalue.into(), 0],
+            &[Opcode::JumpIfFalse.into(), 0, 0],
+            &[Opcode::CloseUpvalue.into()],
+            &[Opcode::Call.into(), 0],
+        ];
+        for instruction in instructions.iter() {
+            let mut vm = VM::with_output(Output::default());
+            let mut chunk = Chunk::new();
+            chunk.add_constant(Value::obj(vm.heap.intern("a")));
+            chunk.write(Opcode::Pop.into(), Span::default());
+            for &byte in instruction.iter() {
+                chunk.write(byte, Span::default());
+            }
+            chunk.write(Opcode::Return.into(), Span::default());
+            let kind = error_kind(vm.interpret_chunk(chunk));
+            assert_eq!(kind, Some(RuntimeErrorKind::StackUnderflow), "{:?}", instruction);
+        }
+    }
+
+    #[test]
+    fn compile_error_details() {
+        let source = "var a = 1;\nprint a";
+        let error = VM::with_output(Output::default()).interpret_named(source, "test.lox");
+        assert_eq!(error, Err(InterpretError::CompileError(vec![Diagnostic {
+            message: "Expect ';' after value".to_owned(),
+            source: Rc::new(Source { name: "test.lox".to_owned(), text: source.to_owned() }),
+            span: Span { line: 2, column: 8, offset: 18, length: 0 },
+            token: ErrorToken::End,
+        }])));
+
+        let diagnostic = &diagnostics("var 1 = 2;")[0];
+        assert_eq!(diagnostic.token, ErrorToken::Lexeme("1".to_owned()));
+        assert_eq!(diagnostic.span, Span { line: 1, column: 5, offset: 4, length: 1 });
+        assert_eq!(diagnostics("print \"open;")[0].token, ErrorToken::Invalid);
+    }
+
+    #[test]
+    fn errors_are_located_at_their_token() {
+        // The parser has already moved on to the next line when it finds the duplicate
+        let diagnostic = &diagnostics("{ var a; var a\n= 1; }")[0];
+        assert_eq!(diagnostic.message, "Already a variable with this name in this scope");
+        assert_eq!((diagnostic.span.line, diagnostic.span.column), (1, 14));
+    }
+
+    #[test]
+    fn every_statement_with_an_error_is_reported() {
+        let source = "
+            print 1 +;
+            var = 2;
+            print (1;
+            print \"fine\";
+            class A < A {}
+        ";
+        let found: Vec<(String, usize)> = diagnostics(source).into_iter()
+            .map(|diagnostic| (diagnostic.message, diagnostic.span.line))
+            .collect();
+        assert_eq!(found, vec![
+            ("Expect expression".to_owned(), 2),
+            ("Expect variable name".to_owned(), 3),
+            ("Expect ')' after expression".to_owned(), 4),
+            ("A class can't inherit from itself".to_owned(), 6),
+        ]);
+        // Nothing runs if there are errors
+        assert_eq!(run("print 1; print;"), Err(Failure::Compile));
+    }
+
+    #[test]
+    fn runtime_error_details() {
+        let source = "fun inner() {\n  return 1 + nil;\n}\nfun outer() { inner(); }\nouter();";
+        let error = runtime_error(source);
+        assert_eq!(error.message, "Operands must be two numbers or two strings");
+        assert_eq!(error.source.name, "<script>");
+        // Errors in operations point at their operator
+        assert_eq!(error.span, Span { line: 2, column: 12, offset: 25, length: 1 });
+        let trace: Vec<(Option<&str>, usize, usize)> = error.trace.iter()
+            .map(|entry| (entry.function.as_deref(), entry.span.line, entry.span.column))
+            .collect();
+        assert_eq!(trace, vec![(Some("inner"), 2, 12), (Some("outer"), 4, 21), (None, 5, 7)]);
+
+        let error = runtime_error("var a = 1;\nprint a < \"b\";");
+        assert_eq!(error.message, "Operands must be numbers");
+        assert_eq!((error.span.line, error.span.column), (2, 9));
+        let error = runtime_error("print -nil;");
+        assert_eq!((error.span.column, error.span.length), (7, 1));
+        let error = runtime_error("print 1 <= \"b\";");
+        assert_eq!((error.span.column, error.span.length), (9, 2));
+        // Columns count characters, starting over after a newline inside a string
+        let error = runtime_error("print \"é\" - 1;");
+        assert_eq!((error.span.column, error.span.offset), (11, 11));
+        let error = runtime_error("var s = \"a\nb\"; print s + nil;");
+        assert_eq!((error.span.line, error.span.column), (2, 13));
+
+        let error = runtime_error("print 1 +\n  missing;");
+        assert_eq!(error.message, "Undefined variable 'missing'");
+        assert_eq!((error.span.line, error.span.column, error.span.length), (2, 3, 7));
+        assert_eq!(runtime_error("fun f(a) {} f();").message, "Expected 1 arguments but got 0");
+
+        let kinds = [
+            ("print -nil;", RuntimeErrorKind::Type),
+            ("nil();", RuntimeErrorKind::Type),
+            ("fun f(a) {} f();", RuntimeErrorKind::Arity),
+            ("print missing;", RuntimeErrorKind::UndefinedVariable),
+            ("class A {} print A().missing;", RuntimeErrorKind::UndefinedProperty),
+            ("sqrt(\"a\");", RuntimeErrorKind::Native),
+        ];
+        for (source, kind) in &kinds {
+            assert_eq!(runtime_error(source).kind, *kind, "{}", source);
+        }
+    }
+
+    #[test]
+    fn comparisons_follow_ieee_754() {
+        let values = [("nan", f64::NAN), ("inf", f64::INFINITY), ("ninf", f64::NEG_INFINITY),
+                      ("nz", -0.0), ("z", 0.0), ("one", 1.0)];
+        type Comparison = fn(&f64, &f64) -> bool;
+        let ops: [(&str, Comparison); 6] = [
+            ("<", PartialOrd::lt), ("<=", PartialOrd::le), (">", PartialOrd::gt),
+            (">=", PartialOrd::ge), ("==", PartialEq::eq), ("!=", PartialEq::ne),
+        ];
+        let mut source = "var nan = 0 / 0; var inf = 1 / 0; var ninf = -1 / 0; var nz = -0; var z = 0; var one = 1;"
+            .to_owned();
+        let mut expected = String::new();
+        for (lhs, a) in &values {
+            for (rhs, b) in &values {
+                for (op, f) in &ops {
+                    source += &format!("print {} {} {};", lhs, op, rhs);
+                    expected += &format!("{}\n", f(a, b));
+                }
+            }
+        }
+        assert_eq!(run(&source), Ok(expected));
+
+        // The same holds when the compiler folds the comparison
+        assert_eq!(run("print 0 / 0 >= 0 / 0; print 0 / 0 <= 1; print 0 / 0 != 0 / 0;"),
+                   Ok("false\nfalse\ntrue\n".to_owned()));
+        assert_eq!(run("print -0 == 0; print -0 < 0; print -0 >= 0; print -1 / 0 < 1 / 0;"),
+                   Ok("true\nfalse\ntrue\ntrue\n".to_owned()));
+    }
+
+    #[test]
+    fn optimized_code_behaves_the_same() {
+        // `run` checks each against the unoptimized code
+        let source = "
+            var i = 0;
+            while (!(i == 3)) {
+                i = i + 1;
+                true;
+                if (!(i != 2)) { print !!(i < 3); } else { nil; }
+            }
+            fun counter() {
+                var n = 0;
+                fun next() { n; n = n + 1; return - -(n * 1); }
+                return next;
+            }
+            var next = counter();
+            next();
+            print next();
+            print !(1 == 1 or 2 == 2);
+        ";
+        assert_eq!(run(source), Ok("true\n2\nfalse\n".to_owned()));
+    }
+
+    #[test]
+    fn folded_operations_match_the_vm() {
+        // Passing an operand through a call keeps the compiler from folding it
+        let cases = [
+            ("-(2 * 3) + 4", "-(id(2) * 3) + 4"),
+            ("\"a\" + \"b\" == \"ab\"", "id(\"a\") + \"b\" == \"ab\""),
+            ("!nil == !0", "!id(nil) == !0"),
+            ("0 / 0 == 0 / 0", "id(0) / 0 == 0 / 0"),
+            ("0 / 0 <= 1", "id(0) / 0 <= 1"),
+            ("-0", "-id(0)"),
+        ];
+        for (folded, computed) in &cases {
+            let folded = run(&format!("print {};", folded));
+            assert_eq!(folded, run(&format!("fun id(v) {{ return v; }} print {};", computed)));
+            assert!(folded.is_ok());
+        }
+
+        // Type errors still happen at runtime
+        for source in &["print 1 + nil;", "print -\"a\";", "print 1 < \"b\";"] {
+            assert_eq!(runtime_error(source).span.line, 1);
+        }
+        assert_eq!(runtime_error("print 1 + nil;").message, "Operands must be two numbers or two strings");
+    }
+
+    #[test]
+    fn runtime_error_resets_vm() {
+        for &backend in BACKENDS.iter() {
+            let out = Output::default();
+            let mut vm = vm_with(backend, out.clone());
+            assert_eq!(failure(vm.interpret("fun f() { return 1 + nil; } f();")), Err(Failure::Runtime));
+            assert_eq!(vm.interpret("fun g() { return 2; } print g();"), Ok(()));
+            assert_eq!(*out.0.borrow(), b"2\n");
+        }
+    }
+
+    #[test]
+    fn invalid_assignment_target() {
+        assert_eq!(run("var a; var b; a + b = 1;"), Err(Failure::Compile));
+    }
+
+    #[test]
+    fn missing_semicolon() {
+        assert_eq!(run("print 1"), Err(Failure::Compile));
+        assert_eq!(run("1 + 2"), Err(Failure::Compile));
+    }
+
+    #[test]
+    fn garbage_is_collected() {
+        for &backend in BACKENDS.iter() {
+            let mut vm = vm_with(backend, Output::default());
+            vm.set_gc_stress(true);
+            vm.maybe_collect();
+            let baseline = vm.heap.live_objects();
+
+            // Each iteration makes a new string and instance that are garbage by the next
+            let source = "
+                class Node {}
+                for (var i = 0; i < 100; i = i + 1) { var n = Node(); n.name = \"n\" + str(i); }
+            ";
+            assert_eq!(vm.interpret(source), Ok(()));
+            vm.maybe_collect();
+            // Only the class and its name are left
+            assert_eq!(vm.heap.live_objects(), baseline + 2);
+        }
+    }
+
+    #[test]
+    fn cycles_are_collected() {
+        for &backend in BACKENDS.iter() {
+            let mut vm = vm_with(backend, Output::default());
+            vm.interpret("class A {} fun link() { var a = A(); var b = A(); a.other = b; b.other = a; }").unwrap();
+            vm.set_gc_stress(true);
+            vm.maybe_collect();
+            let before = vm.heap.live_objects();
+            assert_eq!(vm.interpret("link();"), Ok(()));
+            vm.maybe_collect();
+            assert_eq!(vm.heap.live_objects(), before);
+        }
+    }
+
+    #[test]
+    fn globals_survive_collection() {
+        for &backend in BACKENDS.iter() {
+            let out = Output::default();
+            let mut vm = vm_with(backend, out.clone());
+            vm.set_gc_stress(true);
+            assert_eq!(vm.interpret("var s = \"a\" + \"b\"; fun f() { return s; } class C { m() { return f(); } }"),
+                       Ok(()));
+            assert_eq!(vm.interpret("print C().m() + \"c\";"), Ok(()));
+            assert_eq!(*out.0.borrow(), b"abc\n");
+        }
     }
 }